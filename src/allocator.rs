@@ -1,5 +1,7 @@
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr::null_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use linked_list_allocator::LockedHeap;
 use x86_64::{
     structures::paging::{
         mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
@@ -31,12 +33,47 @@ pub fn init_heap(
     }
 
     unsafe {
-        super::ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        super::ALLOCATOR.heap.lock().init(HEAP_START, HEAP_SIZE);
     }
 
     Ok(())
 }
 
+/// The kernel heap, keeping count of how many bytes are allocated from it.
+pub struct CountingHeap {
+    heap: LockedHeap,
+    used: AtomicUsize,
+}
+
+impl CountingHeap {
+    pub const fn empty() -> CountingHeap {
+        CountingHeap {
+            heap: LockedHeap::empty(),
+            used: AtomicUsize::new(0),
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for CountingHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.heap.alloc(layout);
+        if !ptr.is_null() {
+            self.used.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.heap.dealloc(ptr, layout);
+        self.used.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+/// Returns the number of bytes currently allocated on the kernel heap.
+pub fn heap_used() -> usize {
+    super::ALLOCATOR.used.load(Ordering::Relaxed)
+}
+
 pub struct Dummy;
 
 unsafe impl GlobalAlloc for Dummy {
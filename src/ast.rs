@@ -1,7 +1,8 @@
-use crate::value::LangValue;
-use crate::interpret::{Environment, Evaluatable};
-use alloc::{boxed::Box, string::String, vec::Vec};
-// use crate::interpreter::{TokenType, TokenType::*};
+use crate::value::{self, LangValue};
+use crate::interpret::{Environment, LangError};
+use crate::compile::Compilable;
+use crate::scan::{Span, TokenType, TokenType::*};
+use alloc::{boxed::Box, string::String, vec::Vec, rc::Rc};
 use core::fmt;
 
 fn indent(s: String) -> String {
@@ -14,6 +15,7 @@ fn indent(s: String) -> String {
 
 pub struct Scope {
     pub lines: Vec<DeclOrExpr>,
+    pub span: Span,
 }
 impl fmt::Display for Scope {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -41,7 +43,7 @@ impl fmt::Display for DeclOrExpr {
 
 // {left} = {right};
 pub struct Decl {
-    pub left: Box<dyn Destructure>,
+    pub left: Rc<dyn Destructure>,
     pub right: Box<dyn Expr>,
 }
 impl fmt::Display for Decl {
@@ -51,15 +53,26 @@ impl fmt::Display for Decl {
 }
 
 pub trait Destructure: fmt::Display {
-    fn destruct(&self, env: &mut Environment, val: LangValue);
+    // whether `val` has the shape this pattern expects
+    fn matches(&self, val: &LangValue) -> bool;
+    fn destruct(&self, env: &mut Environment, val: LangValue) -> Result<(), LangError>;
+    fn span(&self) -> Span;
 }
 
 pub struct Identifier {
-    name: String,
+    pub name: Rc<str>,
+    pub span: Span,
 }
 impl Destructure for Identifier {
-    fn destruct(&self, env: &mut Environment, val: LangValue) {
+    fn matches(&self, _val: &LangValue) -> bool {
+        true
+    }
+    fn destruct(&self, env: &mut Environment, val: LangValue) -> Result<(), LangError> {
         env.insert(self.name.clone(), val);
+        Ok(())
+    }
+    fn span(&self) -> Span {
+        self.span
     }
 }
 impl fmt::Display for Identifier {
@@ -68,161 +81,518 @@ impl fmt::Display for Identifier {
     }
 }
 
+// _
+pub struct Wildcard {
+    pub span: Span,
+}
+impl Destructure for Wildcard {
+    fn matches(&self, _val: &LangValue) -> bool {
+        true
+    }
+    fn destruct(&self, _env: &mut Environment, _val: LangValue) -> Result<(), LangError> {
+        Ok(())
+    }
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+impl fmt::Display for Wildcard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "_")
+    }
+}
+
+// a number, string or boolean that must be equal to the value
+pub struct LiteralPattern {
+    pub value: LangValue,
+    pub span: Span,
+}
+impl Destructure for LiteralPattern {
+    fn matches(&self, val: &LangValue) -> bool {
+        self.value == *val
+    }
+    fn destruct(&self, _env: &mut Environment, val: LangValue) -> Result<(), LangError> {
+        if !self.matches(&val) {
+            return Err(LangError::new(format!("Cannot destructure {} into {}", val, self), self.span));
+        }
+        Ok(())
+    }
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+impl fmt::Display for LiteralPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.value)
+    }
+}
+
+// [{items}, ... | {rest}]
+//
+// Without `rest` the list must have exactly as many items as the pattern;
+// with it, `rest` is bound to whatever follows the matched items.
+pub struct ListPattern {
+    pub items: Vec<Box<dyn Destructure>>,
+    pub rest: Option<Box<dyn Destructure>>,
+    pub span: Span,
+}
+impl Destructure for ListPattern {
+    fn matches(&self, val: &LangValue) -> bool {
+        let mut rest = val;
+        for item in self.items.iter() {
+            rest = match rest {
+                LangValue::LangPair(pair) if item.matches(&pair.left) => &pair.right,
+                _ => return false,
+            };
+        }
+        match (&self.rest, rest) {
+            (Some(pattern), _) => pattern.matches(rest),
+            (None, LangValue::LangNone) => true,
+            (None, _) => false,
+        }
+    }
+    fn destruct(&self, env: &mut Environment, val: LangValue) -> Result<(), LangError> {
+        // check the whole shape first so a mismatch never leaves partial bindings
+        if !self.matches(&val) {
+            return Err(LangError::new(format!("Cannot destructure {} into {}", val, self), self.span));
+        }
+
+        let mut rest = val;
+        for item in self.items.iter() {
+            rest = match rest {
+                LangValue::LangPair(pair) => {
+                    item.destruct(env, pair.left.clone())?;
+                    pair.right.clone()
+                },
+                _ => unreachable!(),
+            };
+        }
+        match &self.rest {
+            Some(pattern) => pattern.destruct(env, rest),
+            None => Ok(()),
+        }
+    }
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+impl fmt::Display for ListPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut item_strs: Vec<String> = Vec::new();
+        for item in self.items.iter() {
+            item_strs.push(format!("{}", item));
+        }
+        match &self.rest {
+            Some(rest) => write!(f, "[{} | {}]", item_strs.join(", "), rest),
+            None => write!(f, "[{}]", item_strs.join(", ")),
+        }
+    }
+}
+
+// #[{items}, ... | {rest}]
+//
+// Like a ListPattern, except that `rest` is bound to a vector.
+pub struct VectorPattern {
+    pub items: Vec<Box<dyn Destructure>>,
+    pub rest: Option<Box<dyn Destructure>>,
+    pub span: Span,
+}
+impl VectorPattern {
+    // the items left over for `rest`, if the vector is long enough
+    fn rest_of(&self, items: &[LangValue]) -> Option<LangValue> {
+        if items.len() < self.items.len() {
+            return None;
+        }
+        Some(LangValue::LangVector(Rc::new(items[self.items.len()..].to_vec())))
+    }
+}
+impl Destructure for VectorPattern {
+    fn matches(&self, val: &LangValue) -> bool {
+        let items = match val {
+            LangValue::LangVector(items) => items,
+            _ => return false,
+        };
+        let rest_matches = match &self.rest {
+            Some(pattern) => self.rest_of(items).map_or(false, |rest| pattern.matches(&rest)),
+            None => items.len() == self.items.len(),
+        };
+        rest_matches && self.items.iter().zip(items.iter()).all(|(pattern, item)| pattern.matches(item))
+    }
+    fn destruct(&self, env: &mut Environment, val: LangValue) -> Result<(), LangError> {
+        if !self.matches(&val) {
+            return Err(LangError::new(format!("Cannot destructure {} into {}", val, self), self.span));
+        }
 
-pub trait Expr: fmt::Display + Evaluatable {}
-
-
-// impl Expr for LangValue {
-//     fn eval(&self) -> LangValue {
-//         self.clone()
-//     }
-// }
-// impl Representable for LangValue {
-//     fn repr(&self) -> String {
-//         format!("{:?}", self)
-//     }
-// }
-
-
-
-// // these change the environment
-// pub enum Assign {
-//     DefineFunction
-//     AssignVariable
-// }
-
-// // pub fn repr_lang_val(val: LangValue) -> String {
-// //     unimplemented!()
-// // }
-
-
-
-// pub struct Decl {
-//     pub name: String,
-//     pub params: Vec<String>,
-//     pub body: Box<dyn Expr>,
-// }
-// impl Representable for DefineFunction {
-//     fn repr(&self) -> String {
-//         format!("def {} ({:?}) -> {}", self.name, self.params, self.body.repr())
-//     }
-// }
-
-// pub struct FnCall {
-//     pub name: String,
-//     pub args: Vec<Box<dyn Expr>>,
-// }
-// impl Expr for FnCall {
-//     fn eval(&self) -> LangValue {
-//         LangNone
-//     }
-// }
-// impl Representable for FnCall {
-//     fn repr(&self) -> String {
-//         let mut arg_strs: Vec<String> = Vec::new();
-//         for i in 0..self.args.len() {
-//             arg_strs.push(self.args[i].repr());
-//         }
-//         format!("(do {} {:?})", self.name, arg_strs)
-//     }
-// }
-
-// pub struct Identifier {
-//     pub name: String,
-// }
-// impl Representable for Identifier {
-//     fn repr(&self) -> String {
-//         self.name.clone()
-//     }
-// }
-// impl Expr for Identifier {
-//     fn eval(&self) -> LangValue {
-//         LangNone
-//     }
-// }
-
-// pub struct BinaryExpr {
-//     pub oper: TokenType,
-//     pub left: Box<dyn Expr>,
-//     pub right: Box<dyn Expr>,
-// }
-// impl Representable for BinaryExpr {
-//     fn repr(&self) -> String {
-//         let oper_str = match self.oper {
-//             Star => "*",
-//             Slash => "/",
-//             Plus => "+",
-//             Minus => "-",
-//             _ => "?",
-//         };
-//         format!("({} {} {})", oper_str, self.left.repr(), self.right.repr())
-//     }
-// }
-// impl Expr for BinaryExpr {
-//     fn eval(&self) -> LangValue {
-//         match self.oper {
-//             Star | Slash | Plus | Minus => {
-//                 let left_eval = self.left.eval();
-//                 let left = match left_eval {
-//                     LangNumber(x) => x,
-//                     _ => panic!("NaN {:?}", left_eval),
-//                 };
-//                 let right_eval = self.right.eval();
-//                 let right = match right_eval {
-//                     LangNumber(x) => x,
-//                     _ => panic!("NaN {:?}", right_eval),
-//                 };
-
-//                 LangNumber(match self.oper {
-//                     Plus => left + right,
-//                     Minus => left - right,
-//                     Star => left * right,
-//                     Slash => left / right,
-//                     _ => panic!("Cannot handle {:?}", self.oper),
-//                 })
-//             },
-//             _ => panic!("Cannot eval {:?}", self.oper),
-//         }
-//     }
-// }
-
-// pub struct List {
-//     pub left: Box<dyn Expr>,
-//     pub right: Box<dyn Expr>,
-// }
-// impl Representable for List {
-//     fn repr(&self) -> String {
-//         format!("[{} {}]", self.left.repr(), self.right.repr())
-//     }
-// }
-// impl Expr for List {
-//     fn eval(&self) -> LangValue {
-//         LangPair{
-//             left: Box::new(self.left.eval()),
-//             right: Box::new(self.right.eval()),
-//         }
-//     }
-// }
-
-// pub struct Block {
-//     pub items: Vec<ExprOrDecl>,
-// }
-// impl Representable for Block {
-//     fn repr(&self) -> String {
-//         let mut strs: Vec<String> = Vec::new();
-//         let mut i = 0;
-//         loop {
-//             if i >= self.items.len() {
-//                 break;
-//             }
-//             strs.push(self.items[i].repr());
-//             i += 1;
-//         }
-//         format!("{{{}}}", strs.join("\n"))
-//     }
-// }
-// impl Expr for Block {
-//     fn eval(&self) -> LangValue {
-//         LangNone
-//     }
-// }
+        let items = match val {
+            LangValue::LangVector(items) => items,
+            _ => unreachable!(),
+        };
+        for (pattern, item) in self.items.iter().zip(items.iter()) {
+            pattern.destruct(env, item.clone())?;
+        }
+        match &self.rest {
+            Some(pattern) => pattern.destruct(env, self.rest_of(&items).unwrap()),
+            None => Ok(()),
+        }
+    }
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+impl fmt::Display for VectorPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut item_strs: Vec<String> = Vec::new();
+        for item in self.items.iter() {
+            item_strs.push(format!("{}", item));
+        }
+        match &self.rest {
+            Some(rest) => write!(f, "#[{} | {}]", item_strs.join(", "), rest),
+            None => write!(f, "#[{}]", item_strs.join(", ")),
+        }
+    }
+}
+
+// {{key}: {pattern}, ...}
+//
+// The record must have every key, but may have others too. A key on its own
+// binds the field to a name of the same key.
+pub struct RecordPattern {
+    pub fields: Vec<(String, Box<dyn Destructure>)>,
+    pub span: Span,
+}
+impl Destructure for RecordPattern {
+    fn matches(&self, val: &LangValue) -> bool {
+        match val {
+            LangValue::LangRecord(record) => self.fields.iter().all(|(key, pattern)| {
+                record.get(key).map_or(false, |field| pattern.matches(field))
+            }),
+            _ => false,
+        }
+    }
+    fn destruct(&self, env: &mut Environment, val: LangValue) -> Result<(), LangError> {
+        if !self.matches(&val) {
+            return Err(LangError::new(format!("Cannot destructure {} into {}", val, self), self.span));
+        }
+
+        let record = match val {
+            LangValue::LangRecord(record) => record,
+            _ => unreachable!(),
+        };
+        for (key, pattern) in self.fields.iter() {
+            pattern.destruct(env, record[key].clone())?;
+        }
+        Ok(())
+    }
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+impl fmt::Display for RecordPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut field_strs: Vec<String> = Vec::new();
+        for (key, pattern) in self.fields.iter() {
+            field_strs.push(format!("{}: {}", show_key(key), pattern));
+        }
+        write!(f, "{{{}}}", field_strs.join(", "))
+    }
+}
+
+fn show_key(key: &str) -> String {
+    if value::is_identifier(key) {
+        String::from(key)
+    } else {
+        format!("{:?}", key)
+    }
+}
+
+
+pub trait Expr: fmt::Display + Compilable {
+    fn span(&self) -> Span;
+}
+
+impl Expr for Scope {
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+
+impl Expr for Identifier {
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+
+pub struct Literal {
+    pub value: LangValue,
+    pub span: Span,
+}
+impl Expr for Literal {
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+impl fmt::Display for Literal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.value)
+    }
+}
+
+// \{param} -> {body}
+pub struct Lambda {
+    // the function it's part of, if declared as `name x y = ...`
+    pub name: Option<Rc<str>>,
+    // whether it's the outermost lambda of the declaration, which is bound
+    // to the name
+    pub binds_self: bool,
+    pub param: Rc<dyn Destructure>,
+    pub body: Rc<dyn Expr>,
+    pub span: Span,
+}
+impl Expr for Lambda {
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+impl fmt::Display for Lambda {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\\{} ->\n{}", self.param, indent(format!("{}", self.body)))
+    }
+}
+
+// {func} {arg}
+pub struct Call {
+    pub func: Box<dyn Expr>,
+    pub arg: Box<dyn Expr>,
+}
+impl Expr for Call {
+    fn span(&self) -> Span {
+        self.func.span().to(self.arg.span())
+    }
+}
+impl fmt::Display for Call {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "({} {})", self.func, self.arg)
+    }
+}
+
+// if {cond} then {then_branch} else {else_branch}
+pub struct IfElse {
+    pub cond: Box<dyn Expr>,
+    pub then_branch: Box<dyn Expr>,
+    pub else_branch: Box<dyn Expr>,
+    pub span: Span,
+}
+impl Expr for IfElse {
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+impl fmt::Display for IfElse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "if {} then\n{}\nelse\n{}", self.cond,
+            indent(format!("{}", self.then_branch)),
+            indent(format!("{}", self.else_branch)))
+    }
+}
+
+// {pattern} -> {body}
+pub struct CaseArm {
+    pub pattern: Rc<dyn Destructure>,
+    pub body: Box<dyn Expr>,
+}
+
+// case {value} of {arms}, ...
+pub struct CaseOf {
+    pub value: Box<dyn Expr>,
+    pub arms: Vec<CaseArm>,
+    pub span: Span,
+}
+impl Expr for CaseOf {
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+impl fmt::Display for CaseOf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut arm_strs: Vec<String> = Vec::new();
+        for arm in self.arms.iter() {
+            arm_strs.push(indent(format!("{} ->\n{}", arm.pattern, indent(format!("{}", arm.body)))));
+        }
+        write!(f, "case {} of\n{}", self.value, arm_strs.join(",\n"))
+    }
+}
+
+pub enum TemplatePart {
+    Text(String),
+    Interpolation(Box<dyn Expr>),
+}
+
+// `text $(expr) ...`
+pub struct Template {
+    pub parts: Vec<TemplatePart>,
+    pub span: Span,
+}
+impl Expr for Template {
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`")?;
+        for part in self.parts.iter() {
+            match part {
+                TemplatePart::Text(text) => write!(f, "{}", text)?,
+                TemplatePart::Interpolation(expr) => write!(f, "$({})", expr)?,
+            }
+        }
+        write!(f, "`")
+    }
+}
+
+// map {func} {list}
+pub struct Map {
+    pub func: Box<dyn Expr>,
+    pub list: Box<dyn Expr>,
+    pub span: Span,
+}
+impl Expr for Map {
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+impl fmt::Display for Map {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(map {} {})", self.func, self.list)
+    }
+}
+
+// {left} {oper} {right}
+pub struct BinaryExpr {
+    pub oper: TokenType,
+    pub left: Box<dyn Expr>,
+    pub right: Box<dyn Expr>,
+}
+impl Expr for BinaryExpr {
+    fn span(&self) -> Span {
+        self.left.span().to(self.right.span())
+    }
+}
+impl fmt::Display for BinaryExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let oper_str = match self.oper {
+            Star => "*",
+            Slash => "/",
+            Plus => "+",
+            Minus => "-",
+            PlusPlus => "++",
+            EqualEqual => "==",
+            NotEqual => "!=",
+            Less => "<",
+            LessEq => "<=",
+            Greater => ">",
+            GreaterEq => ">=",
+            And => "&&",
+            Or => "||",
+            PipeForwards => "|>",
+            ComposeForwards => ">>",
+            _ => "?",
+        };
+        write!(f, "({} {} {})", self.left, oper_str, self.right)
+    }
+}
+
+// {oper}{operand}
+pub struct UnaryExpr {
+    pub oper: TokenType,
+    pub operand: Box<dyn Expr>,
+    pub span: Span,
+}
+impl Expr for UnaryExpr {
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+impl fmt::Display for UnaryExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let oper_str = match self.oper {
+            Minus => "-",
+            Bang => "!",
+            _ => "?",
+        };
+        write!(f, "({}{})", oper_str, self.operand)
+    }
+}
+
+// [{left}, ...{right}]
+pub struct List {
+    pub left: Box<dyn Expr>,
+    pub right: Box<dyn Expr>,
+    pub span: Span,
+}
+impl Expr for List {
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+impl fmt::Display for List {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{} {}]", self.left, self.right)
+    }
+}
+
+// import "{path}"
+pub struct ImportExpr {
+    pub path: String,
+    pub span: Span,
+}
+impl Expr for ImportExpr {
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+impl fmt::Display for ImportExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(import {:?})", self.path)
+    }
+}
+
+// #[{items}, ...]
+pub struct Vector {
+    pub items: Vec<Box<dyn Expr>>,
+    pub span: Span,
+}
+impl Expr for Vector {
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+impl fmt::Display for Vector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut item_strs: Vec<String> = Vec::new();
+        for item in self.items.iter() {
+            item_strs.push(format!("{}", item));
+        }
+        write!(f, "#[{}]", item_strs.join(", "))
+    }
+}
+
+// {{key}: {value}, ...}
+pub struct Record {
+    pub fields: Vec<(String, Box<dyn Expr>)>,
+    pub span: Span,
+}
+impl Expr for Record {
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut field_strs: Vec<String> = Vec::new();
+        for (key, value) in self.fields.iter() {
+            field_strs.push(format!("{}: {}", show_key(key), value));
+        }
+        write!(f, "{{{}}}", field_strs.join(", "))
+    }
+}
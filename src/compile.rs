@@ -0,0 +1,567 @@
+use alloc::{vec::Vec, string::String, rc::Rc};
+use core::{convert::TryFrom, fmt};
+use crate::ast::*;
+use crate::interpret::LangError;
+use crate::scan::{Span, TokenType::*};
+use crate::value::LangValue;
+
+// One instruction for the stack machine in `vm`. Operands index into the
+// tables of the chunk holding the instruction, or into its code for jumps.
+#[derive(Debug)]
+#[derive(Copy)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub enum Op {
+    // push `constants[i]`
+    Constant(u16),
+    // push LangNone
+    Nil,
+    // push the value bound to `names[i]`
+    Load(u16),
+    // pop a value and destructure it into `patterns[i]`
+    Bind(u16),
+    Pop,
+    // swap the top two values
+    Swap,
+    // save the environment until the matching PopScope
+    PushScope,
+    PopScope,
+    // push a closure of `functions[i]` over the environment
+    Closure(u16),
+    // bind the closure on top of the stack in the environment of the closure
+    // bound to `names[i]` too, if that is still the closure of `functions[j]`,
+    // so functions declared together see each other
+    Link(u16, u16),
+    // pop an argument and a function, and push the result of the call
+    Call,
+    // like Call, but replace the current frame since its result is returned as is
+    TailCall,
+    // leave the function, leaving its result on the stack
+    Return,
+    // pop the right operand and then the left, and push the result
+    Add, Subtract, Multiply, Divide, Concat,
+    Equal, NotEqual, Less, LessEqual, Greater, GreaterEqual,
+    // replace the top value with its negation
+    Negate, Not,
+    // pop `g` and `f`, and push `f >> g`
+    Compose,
+    // pop `right` and `left`, and push the pair
+    Pair,
+    Jump(u16),
+    // pop a boolean, and jump if it is false
+    JumpIfFalse(u16),
+    // if the top value matches `patterns[i]`, pop it into a new scope,
+    // otherwise jump
+    Case(u16, u16),
+    // fail on the value that no case matched
+    NoCase,
+    // pop `n` values and join them into a string
+    Template(u16),
+    // pop a list and a function, and push the function applied to each item
+    Map,
+    // pop `n` values into a vector
+    Vector(u16),
+    // pop `n` pairs of a string key and its value into a record
+    Record(u16),
+    // push the module at the path in `constants[i]`
+    Import(u16),
+}
+
+// Compiled code, with `spans[i]` locating `code[i]` for error messages.
+pub struct Chunk {
+    pub code: Vec<Op>,
+    pub spans: Vec<Span>,
+    pub constants: Vec<LangValue>,
+    pub names: Vec<String>,
+    pub patterns: Vec<Rc<dyn Destructure>>,
+    pub functions: Vec<Rc<Function>>,
+}
+
+// The compiled body of a lambda. Functions of several parameters are
+// curried, so `f x y z = x + y*z` compiles to
+//
+// \x -> {
+//     \y -> {
+//         \z -> {
+//             x + y*z
+//         }
+//     }
+// }
+pub struct Function {
+    // the declared function it's part of, to label its frames
+    pub name: Option<Rc<str>>,
+    // set for the outermost one, so that it can call itself
+    pub binds_self: bool,
+    pub param: Rc<dyn Destructure>,
+    pub chunk: Rc<Chunk>,
+}
+
+impl Function {
+    // The name it's bound to, or its parameter for the inner levels of a
+    // declared function, like `\y` for `add x y = ...` applied to one
+    // argument.
+    pub fn label(&self) -> String {
+        match &self.name {
+            Some(name) if self.binds_self => String::from(&**name),
+            _ => format!("\\{}", self.param),
+        }
+    }
+}
+
+pub trait Compilable {
+    fn compile(&self, chunk: &mut Chunk) -> Result<(), LangError>;
+}
+
+// Compiles a whole file. Unlike a block's, its declarations are made
+// directly in the environment it runs in.
+pub fn compile_script(scope: &Scope) -> Result<Rc<Chunk>, LangError> {
+    let mut chunk = Chunk::new();
+    compile_lines(scope, &mut chunk)?;
+    chunk.emit(Op::Return, scope.span);
+    Ok(chunk.finish())
+}
+
+// Leaves the value of the last line on the stack. The functions declared in
+// the scope can call each other: each one is linked into the ones declared
+// before it, and closes over the ones declared before it anyway. Only the
+// closures the scope made itself are linked into, never one that the name
+// was rebound to since, like one from an import.
+//
+// Linking only reaches the outermost level of a curried function, so a
+// partial application made before a later function is declared can't see
+// it: in `f x y = g y; h = f 1; g y = 2; h 3`, `h` was closed over what `f`
+// saw when it was applied, and `g` is unbound, while `f 1 3` finds it.
+fn compile_lines(scope: &Scope, chunk: &mut Chunk) -> Result<(), LangError> {
+    if scope.lines.is_empty() {
+        chunk.emit(Op::Nil, scope.span);
+    }
+    // the name and function of each declared function, newest per name
+    let mut functions: Vec<(Rc<str>, u16)> = Vec::new();
+    for i in 0..scope.lines.len() {
+        let last = i == scope.lines.len()-1;
+        match &scope.lines[i] {
+            DeclOrExpr::Declaration(decl) => {
+                decl.right.compile(chunk)?;
+                if let Some((name, function)) = chunk.declared_function() {
+                    // a redeclared function replaces the old one, which
+                    // keeps calling itself
+                    functions.retain(|(earlier, _)| *earlier != name);
+                    for (earlier, earlier_function) in functions.iter() {
+                        let earlier = chunk.add_name(earlier, decl.left.span())?;
+                        chunk.emit(Op::Link(earlier, *earlier_function), decl.left.span());
+                    }
+                    functions.push((name, function));
+                }
+                let pattern = chunk.add_pattern(decl.left.clone())?;
+                chunk.emit(Op::Bind(pattern), decl.left.span());
+                if last {
+                    chunk.emit(Op::Nil, scope.span);
+                }
+            },
+            DeclOrExpr::Expression(expr) => {
+                expr.compile(chunk)?;
+                if !last {
+                    chunk.emit(Op::Pop, expr.span());
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+impl Chunk {
+    pub fn new() -> Chunk {
+        Chunk {
+            code: Vec::new(),
+            spans: Vec::new(),
+            constants: Vec::new(),
+            names: Vec::new(),
+            patterns: Vec::new(),
+            functions: Vec::new(),
+        }
+    }
+
+    // Chunks last as long as the closures made from them, so they give back
+    // the room they grew into while compiling.
+    fn finish(mut self) -> Rc<Chunk> {
+        self.code.shrink_to_fit();
+        self.spans.shrink_to_fit();
+        self.constants.shrink_to_fit();
+        self.names.shrink_to_fit();
+        self.patterns.shrink_to_fit();
+        self.functions.shrink_to_fit();
+        Rc::new(self)
+    }
+
+    // Returns where `op` was written, for patching jumps.
+    fn emit(&mut self, op: Op, span: Span) -> usize {
+        self.code.push(op);
+        self.spans.push(span);
+        self.code.len() - 1
+    }
+
+    fn emit_constant(&mut self, value: LangValue, span: Span) -> Result<(), LangError> {
+        self.constants.push(value);
+        let i = operand(self.constants.len() - 1, "constants", span)?;
+        self.emit(Op::Constant(i), span);
+        Ok(())
+    }
+
+    fn add_name(&mut self, name: &str, span: Span) -> Result<u16, LangError> {
+        let i = match self.names.iter().position(|x| x == name) {
+            Some(i) => i,
+            None => {
+                self.names.push(String::from(name));
+                self.names.len() - 1
+            },
+        };
+        operand(i, "names", span)
+    }
+
+    fn add_pattern(&mut self, pattern: Rc<dyn Destructure>) -> Result<u16, LangError> {
+        let span = pattern.span();
+        self.patterns.push(pattern);
+        operand(self.patterns.len() - 1, "patterns", span)
+    }
+
+    // The name and index of the function the last instruction made, if it
+    // was a declared one, as in `f x = ...`.
+    fn declared_function(&self) -> Option<(Rc<str>, u16)> {
+        match self.code.last() {
+            Some(Op::Closure(i)) => {
+                let function = &self.functions[*i as usize];
+                if function.binds_self { function.name.clone().map(|name| (name, *i)) } else { None }
+            },
+            _ => None,
+        }
+    }
+
+    // Points the jump at `at` to the next instruction.
+    fn patch_jump(&mut self, at: usize, span: Span) -> Result<(), LangError> {
+        let target = operand(self.code.len(), "instructions", span)?;
+        self.code[at] = match self.code[at] {
+            Op::Jump(_) => Op::Jump(target),
+            Op::JumpIfFalse(_) => Op::JumpIfFalse(target),
+            Op::Case(pattern, _) => Op::Case(pattern, target),
+            op => panic!("Cannot patch {:?}", op),
+        };
+        Ok(())
+    }
+}
+
+// Turns the calls whose result is returned straight away into tail calls,
+// so functions can recurse in tail position forever. Only function bodies
+// get them: a script has no frame to give up, since its environment is kept.
+fn mark_tail_calls(chunk: &mut Chunk) {
+    for i in 0..chunk.code.len() {
+        if chunk.code[i] == Op::Call && returns_from(chunk, i + 1) {
+            chunk.code[i] = Op::TailCall;
+        }
+    }
+}
+
+// Whether running from `at` returns without doing anything else. Jumps only
+// go forwards, so this always ends.
+fn returns_from(chunk: &Chunk, mut at: usize) -> bool {
+    loop {
+        match chunk.code[at] {
+            Op::PopScope => at += 1,
+            Op::Jump(target) => at = target as usize,
+            Op::Return => return true,
+            _ => return false,
+        }
+    }
+}
+
+fn operand(i: usize, table: &str, span: Span) -> Result<u16, LangError> {
+    u16::try_from(i)
+        .map_err(|_| LangError::new(format!("Too many {} in one function", table), span))
+}
+
+impl Compilable for Scope {
+    fn compile(&self, chunk: &mut Chunk) -> Result<(), LangError> {
+        chunk.emit(Op::PushScope, self.span);
+        compile_lines(self, chunk)?;
+        chunk.emit(Op::PopScope, self.span);
+        Ok(())
+    }
+}
+
+impl Compilable for Literal {
+    fn compile(&self, chunk: &mut Chunk) -> Result<(), LangError> {
+        chunk.emit_constant(self.value.clone(), self.span)
+    }
+}
+
+impl Compilable for Identifier {
+    fn compile(&self, chunk: &mut Chunk) -> Result<(), LangError> {
+        let name = chunk.add_name(&self.name, self.span)?;
+        chunk.emit(Op::Load(name), self.span);
+        Ok(())
+    }
+}
+
+impl Compilable for Lambda {
+    fn compile(&self, chunk: &mut Chunk) -> Result<(), LangError> {
+        let mut body = Chunk::new();
+        self.body.compile(&mut body)?;
+        body.emit(Op::Return, self.body.span());
+        mark_tail_calls(&mut body);
+
+        chunk.functions.push(Rc::new(Function {
+            name: self.name.clone(),
+            binds_self: self.binds_self,
+            param: self.param.clone(),
+            chunk: body.finish(),
+        }));
+        let function = operand(chunk.functions.len() - 1, "functions", self.span)?;
+        chunk.emit(Op::Closure(function), self.span);
+        Ok(())
+    }
+}
+
+impl Compilable for Call {
+    fn compile(&self, chunk: &mut Chunk) -> Result<(), LangError> {
+        self.func.compile(chunk)?;
+        self.arg.compile(chunk)?;
+        chunk.emit(Op::Call, self.func.span());
+        Ok(())
+    }
+}
+
+impl Compilable for BinaryExpr {
+    fn compile(&self, chunk: &mut Chunk) -> Result<(), LangError> {
+        match self.oper {
+            And | Or => return self.compile_logic(chunk),
+            _ => (),
+        }
+
+        self.left.compile(chunk)?;
+        self.right.compile(chunk)?;
+        match self.oper {
+            // x |> f == f x
+            PipeForwards => {
+                chunk.emit(Op::Swap, self.span());
+                chunk.emit(Op::Call, self.right.span());
+            },
+            ComposeForwards => {
+                chunk.emit(Op::Compose, self.span());
+            },
+            _ => {
+                let op = match self.oper {
+                    Plus => Op::Add,
+                    Minus => Op::Subtract,
+                    Star => Op::Multiply,
+                    Slash => Op::Divide,
+                    PlusPlus => Op::Concat,
+                    EqualEqual => Op::Equal,
+                    NotEqual => Op::NotEqual,
+                    Less => Op::Less,
+                    LessEq => Op::LessEqual,
+                    Greater => Op::Greater,
+                    GreaterEq => Op::GreaterEqual,
+                    _ => return Err(LangError::new(
+                        format!("Cannot compile {:?}", self.oper), self.span())),
+                };
+                chunk.emit(op, self.span());
+            },
+        }
+        Ok(())
+    }
+}
+
+impl BinaryExpr {
+    // Compiles `a && b` like `if a then (if b then true else false) else false`
+    // and `a || b` like `if a then true else (if b then true else false)`.
+    fn compile_logic(&self, chunk: &mut Chunk) -> Result<(), LangError> {
+        let span = self.span();
+        let mut to_true: Vec<usize> = Vec::new();
+        let mut to_false: Vec<usize> = Vec::new();
+
+        self.left.compile(chunk)?;
+        let left_false = chunk.emit(Op::JumpIfFalse(0), self.left.span());
+        if self.oper == And {
+            to_false.push(left_false);
+        } else {
+            to_true.push(chunk.emit(Op::Jump(0), span));
+            chunk.patch_jump(left_false, span)?;
+        }
+
+        self.right.compile(chunk)?;
+        to_false.push(chunk.emit(Op::JumpIfFalse(0), self.right.span()));
+        for at in to_true {
+            chunk.patch_jump(at, span)?;
+        }
+        chunk.emit_constant(LangValue::LangBool(true), span)?;
+        let to_end = chunk.emit(Op::Jump(0), span);
+        for at in to_false {
+            chunk.patch_jump(at, span)?;
+        }
+        chunk.emit_constant(LangValue::LangBool(false), span)?;
+        chunk.patch_jump(to_end, span)
+    }
+}
+
+impl Compilable for UnaryExpr {
+    fn compile(&self, chunk: &mut Chunk) -> Result<(), LangError> {
+        self.operand.compile(chunk)?;
+        let op = match self.oper {
+            Minus => Op::Negate,
+            Bang => Op::Not,
+            _ => return Err(LangError::new(format!("Cannot compile {:?}", self.oper), self.span)),
+        };
+        chunk.emit(op, self.span);
+        Ok(())
+    }
+}
+
+impl Compilable for IfElse {
+    fn compile(&self, chunk: &mut Chunk) -> Result<(), LangError> {
+        self.cond.compile(chunk)?;
+        let to_else = chunk.emit(Op::JumpIfFalse(0), self.cond.span());
+        self.then_branch.compile(chunk)?;
+        let to_end = chunk.emit(Op::Jump(0), self.span);
+        chunk.patch_jump(to_else, self.span)?;
+        self.else_branch.compile(chunk)?;
+        chunk.patch_jump(to_end, self.span)
+    }
+}
+
+impl Compilable for CaseOf {
+    fn compile(&self, chunk: &mut Chunk) -> Result<(), LangError> {
+        self.value.compile(chunk)?;
+        let mut to_end: Vec<usize> = Vec::new();
+        for arm in self.arms.iter() {
+            let pattern = chunk.add_pattern(arm.pattern.clone())?;
+            let to_next = chunk.emit(Op::Case(pattern, 0), arm.pattern.span());
+            arm.body.compile(chunk)?;
+            chunk.emit(Op::PopScope, arm.body.span());
+            to_end.push(chunk.emit(Op::Jump(0), arm.body.span()));
+            chunk.patch_jump(to_next, self.span)?;
+        }
+        chunk.emit(Op::NoCase, self.span);
+        for at in to_end {
+            chunk.patch_jump(at, self.span)?;
+        }
+        Ok(())
+    }
+}
+
+impl Compilable for Template {
+    fn compile(&self, chunk: &mut Chunk) -> Result<(), LangError> {
+        for part in self.parts.iter() {
+            match part {
+                TemplatePart::Text(text) =>
+                    chunk.emit_constant(LangValue::LangString(Rc::from(text.as_str())), self.span)?,
+                TemplatePart::Interpolation(expr) => expr.compile(chunk)?,
+            }
+        }
+        let parts = operand(self.parts.len(), "template parts", self.span)?;
+        chunk.emit(Op::Template(parts), self.span);
+        Ok(())
+    }
+}
+
+impl Compilable for Map {
+    fn compile(&self, chunk: &mut Chunk) -> Result<(), LangError> {
+        self.func.compile(chunk)?;
+        self.list.compile(chunk)?;
+        chunk.emit(Op::Map, self.span);
+        Ok(())
+    }
+}
+
+impl Compilable for List {
+    fn compile(&self, chunk: &mut Chunk) -> Result<(), LangError> {
+        self.left.compile(chunk)?;
+        self.right.compile(chunk)?;
+        chunk.emit(Op::Pair, self.span);
+        Ok(())
+    }
+}
+
+impl Compilable for Vector {
+    fn compile(&self, chunk: &mut Chunk) -> Result<(), LangError> {
+        for item in self.items.iter() {
+            item.compile(chunk)?;
+        }
+        let items = operand(self.items.len(), "vector items", self.span)?;
+        chunk.emit(Op::Vector(items), self.span);
+        Ok(())
+    }
+}
+
+impl Compilable for Record {
+    fn compile(&self, chunk: &mut Chunk) -> Result<(), LangError> {
+        for (key, value) in self.fields.iter() {
+            chunk.emit_constant(LangValue::LangString(Rc::from(key.as_str())), value.span())?;
+            value.compile(chunk)?;
+        }
+        let fields = operand(self.fields.len(), "record fields", self.span)?;
+        chunk.emit(Op::Record(fields), self.span);
+        Ok(())
+    }
+}
+
+impl Compilable for ImportExpr {
+    fn compile(&self, chunk: &mut Chunk) -> Result<(), LangError> {
+        chunk.constants.push(LangValue::LangString(Rc::from(self.path.as_str())));
+        let path = operand(chunk.constants.len() - 1, "constants", self.span)?;
+        chunk.emit(Op::Import(path), self.span);
+        Ok(())
+    }
+}
+
+// Lists every instruction with its source line and what its operand refers
+// to, followed by each function the chunk creates.
+impl fmt::Display for Chunk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for i in 0..self.code.len() {
+            let line = if i > 0 && self.spans[i].line == self.spans[i-1].line {
+                String::from("   |")
+            } else {
+                format!("{:4}", self.spans[i].line)
+            };
+            let detail = match self.code[i] {
+                Op::Constant(x) | Op::Import(x) => format!("{}", self.constants[x as usize]),
+                Op::Load(x) | Op::Link(x, _) => self.names[x as usize].clone(),
+                Op::Bind(x) | Op::Case(x, _) => format!("{}", self.patterns[x as usize]),
+                Op::Closure(x) => self.functions[x as usize].label(),
+                _ => String::new(),
+            };
+            let op = format!("{:?}", self.code[i]);
+            writeln!(f, "{}", format!("{:04} {} {:<14} {}", i, line, op, detail).trim_end())?;
+        }
+        for function in self.functions.iter() {
+            write!(f, "\n== {} ==\n{}", function.label(), function.chunk)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+use rust_os::{serial_print, serial_println};
+
+#[test_case]
+fn test_compile_disassemble() {
+    use crate::lang;
+
+    serial_print!("test_compile_disassemble... ");
+    let text = String::from("double x = x * 2;\ndouble 3");
+    let chunk = lang::compile(&text).expect("compile failed");
+    assert_eq!(format!("{}", chunk), "\
+0000    1 Closure(0)     double
+0001    | Bind(0)        double
+0002    2 Load(0)        double
+0003    | Constant(0)    3
+0004    | Call
+0005    1 Return
+
+== double ==
+0000    1 Load(0)        x
+0001    | Constant(0)    2
+0002    | Multiply
+0003    | Return
+");
+    serial_println!("[ok]");
+}
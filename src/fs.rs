@@ -0,0 +1,80 @@
+use alloc::{boxed::Box, collections::BTreeMap, string::String};
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// A source of files, mounted with `mount` so readers don't depend on
+/// where the files are stored.
+pub trait FileSystem {
+    /// Returns the contents of the file at `path`, if there is one.
+    fn read(&self, path: &str) -> Option<String>;
+}
+
+#[derive(Debug)]
+pub enum FsError {
+    NotMounted,
+    NotFound,
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsError::NotMounted => write!(f, "no filesystem is mounted"),
+            FsError::NotFound => write!(f, "no such file"),
+        }
+    }
+}
+
+static MOUNTED: Mutex<Option<Box<dyn FileSystem + Send>>> = Mutex::new(None);
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// Mounts `fs`, replacing whatever was mounted before. Reads fail with
+/// `FsError::NotMounted` while nothing is.
+pub fn mount(fs: Box<dyn FileSystem + Send>) {
+    *MOUNTED.lock() = Some(fs);
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Unmounts the mounted filesystem, if there is one.
+pub fn unmount() {
+    *MOUNTED.lock() = None;
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Counts the mounts and unmounts so far, so that anything cached from
+/// files can tell when they may have changed underneath it.
+pub fn generation() -> usize {
+    GENERATION.load(Ordering::SeqCst)
+}
+
+/// Reads the whole file at `path` from the mounted filesystem.
+pub fn read(path: &str) -> Result<String, FsError> {
+    match MOUNTED.lock().as_ref() {
+        Some(fs) => fs.read(path).ok_or(FsError::NotFound),
+        None => Err(FsError::NotMounted),
+    }
+}
+
+/// A filesystem kept on the heap, for files put there at runtime.
+pub struct MemoryFs {
+    files: BTreeMap<String, String>,
+}
+
+impl MemoryFs {
+    pub fn new() -> MemoryFs {
+        MemoryFs {
+            files: BTreeMap::new(),
+        }
+    }
+
+    /// Creates the file at `path`, or replaces its contents.
+    pub fn write(&mut self, path: &str, contents: &str) {
+        self.files.insert(String::from(path), String::from(contents));
+    }
+}
+
+impl FileSystem for MemoryFs {
+    fn read(&self, path: &str) -> Option<String> {
+        self.files.get(path).cloned()
+    }
+}
@@ -0,0 +1,291 @@
+use alloc::{collections::BTreeSet, rc::Rc, vec::Vec};
+use core::{cell::{Cell, RefCell}, cmp, sync::atomic::{AtomicU32, Ordering}};
+use rust_os::allocator;
+use crate::compile::Function;
+use crate::interpret::Environment;
+use crate::value::LangValue::{self, *};
+use crate::vm::Closure;
+
+// Closures live in a heap that owns them: the VM makes every one through
+// `Heap::alloc`, and a closure stays allocated until a collection finds
+// that nothing reachable from the roots refers to it any more, no matter
+// how its environment refers back to it. A recursive function is closed
+// over its own binding, and over the functions declared with it, so the
+// cycles that makes are freed like any other garbage.
+//
+// Collections mark and sweep. Marking starts from the roots: the shell's
+// environment between lines, and while a script runs, everything the VM
+// holds, including the VMs waiting on an import, and the modules imported
+// so far. It traces through environments and values to every closure they
+// reach. Sweeping empties the environments of the closures left unmarked,
+// so their cycles come apart, and lets go of them.
+//
+// Everything else a value holds, like strings and lists, can't be changed
+// once made, so it can't refer back to itself except through a closure and
+// is still freed by reference counting as soon as it's unused.
+//
+// A script that keeps making closures could fill the heap long before the
+// shell gets a chance to collect between lines, so the VM also collects
+// before it makes one once the heap is filling up.
+
+pub struct Heap {
+    closures: Vec<Rc<Closure>>,
+    // how full the kernel heap can get before the next collection
+    threshold: usize,
+}
+
+// Numbers collections across every heap, so marks left by an earlier one,
+// or by another heap's, never pass for marks from the one running.
+static EPOCH: AtomicU32 = AtomicU32::new(0);
+
+// Kept in each closure and binding, so marking doesn't need room for an
+// entry per object when it runs with the heap nearly full.
+pub struct Mark {
+    // the collection that last reached the object
+    epoch: Cell<u32>,
+}
+
+// Marks what a collection's roots reach. The heap hands one out, the roots
+// are given to it, and it goes back to the heap to sweep.
+pub struct Marker {
+    epoch: u32,
+    // how much of the kernel heap was used when the collection started
+    before: usize,
+    // the closures marked, whose environments haven't been traced yet
+    closures: Vec<Rc<Closure>>,
+    // the addresses of the shared pairs, vectors and the like traced
+    // already, so a list shared by many others is only traced once
+    seen: BTreeSet<usize>,
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap {
+            closures: Vec::new(),
+            threshold: allocator::HEAP_SIZE / 2,
+        }
+    }
+
+    // Makes a closure of `function` over `env`.
+    pub fn alloc(&mut self, function: Rc<Function>, env: Environment) -> Rc<Closure> {
+        let closure = Rc::new(Closure { function, env: RefCell::new(env), mark: Mark::new() });
+        self.closures.push(closure.clone());
+        closure
+    }
+
+    // Whether the kernel heap has filled up past the threshold.
+    pub fn is_full(&self) -> bool {
+        allocator::heap_used() > self.threshold
+    }
+
+    // Starts a collection.
+    pub fn marker(&self) -> Marker {
+        Marker {
+            epoch: EPOCH.fetch_add(1, Ordering::Relaxed).wrapping_add(1),
+            before: allocator::heap_used(),
+            closures: Vec::new(),
+            seen: BTreeSet::new(),
+        }
+    }
+
+    // Finishes the collection `marker` was given the roots for, freeing the
+    // closures it didn't reach and returning how many bytes of the kernel
+    // heap that reclaimed.
+    pub fn sweep(&mut self, mut marker: Marker) -> usize {
+        marker.trace();
+        let (epoch, before) = (marker.epoch, marker.before);
+        drop(marker);
+
+        // every closure is still held by the heap until all the cycles are
+        // broken, so none is freed while another's environment is emptied
+        for closure in self.closures.iter() {
+            if !closure.mark.is(epoch) {
+                *closure.env.borrow_mut() = Environment::new();
+            }
+        }
+        self.closures.retain(|closure| closure.mark.is(epoch));
+        self.closures.shrink_to_fit();
+
+        // wait for the heap to grow by another eighth before collecting
+        // again, so a heap kept full by live values doesn't make every new
+        // closure trace all the others
+        let used = allocator::heap_used();
+        self.threshold = cmp::min(
+            cmp::max(used + allocator::HEAP_SIZE / 8, allocator::HEAP_SIZE / 2),
+            allocator::HEAP_SIZE * 7 / 8,
+        );
+        before.saturating_sub(used)
+    }
+}
+
+// Closures are only called while the runtime that made them runs, so they
+// go along with its heap, whatever still refers to them.
+impl Drop for Heap {
+    fn drop(&mut self) {
+        for closure in self.closures.iter() {
+            *closure.env.borrow_mut() = Environment::new();
+        }
+    }
+}
+
+impl Marker {
+    pub fn env(&mut self, env: &Environment) {
+        let mut node = env.head();
+        while let Some(binding) = node {
+            // environments share their older bindings, and the ones after a
+            // binding that's been reached have been traced along with it
+            if !binding.mark.reach(self.epoch) {
+                break;
+            }
+            self.value(&binding.value);
+            node = binding.next.as_ref();
+        }
+    }
+
+    pub fn values<'v>(&mut self, vals: impl IntoIterator<Item = &'v LangValue>) {
+        for val in vals {
+            self.value(val);
+        }
+    }
+
+    pub fn value(&mut self, val: &LangValue) {
+        let mut pending = vec![val];
+        while let Some(val) = pending.pop() {
+            match val {
+                // the tail goes first, so a long list's items are taken off
+                // one at a time rather than piling up
+                LangPair(pair) if self.first_visit(pair) => {
+                    pending.push(&pair.right);
+                    pending.push(&pair.left);
+                },
+                LangVector(items) if self.first_visit(items) => pending.extend(items.iter()),
+                LangRecord(fields) if self.first_visit(fields) => pending.extend(fields.values()),
+                LangComposed{first, second} => {
+                    if self.first_visit(first) {
+                        pending.push(first);
+                    }
+                    if self.first_visit(second) {
+                        pending.push(second);
+                    }
+                },
+                Native{args, ..} if self.first_visit(args) => pending.extend(args.iter()),
+                LangClosure(closure) if closure.mark.reach(self.epoch) => self.closures.push(closure.clone()),
+                _ => (),
+            }
+        }
+    }
+
+    // Whether this is the first time the collection has reached `shared`.
+    // Something with one owner can only be reached through it.
+    fn first_visit<T: ?Sized>(&mut self, shared: &Rc<T>) -> bool {
+        Rc::strong_count(shared) == 1 || self.seen.insert(Rc::as_ptr(shared) as *const () as usize)
+    }
+
+    // Traces through the environments of the closures marked so far, and
+    // of the ones those reach.
+    fn trace(&mut self) {
+        while let Some(closure) = self.closures.pop() {
+            let env = closure.env.borrow();
+            self.env(&env);
+        }
+    }
+}
+
+impl Mark {
+    pub fn new() -> Mark {
+        Mark { epoch: Cell::new(0) }
+    }
+
+    // Marks the object for collection `epoch`, returning whether it wasn't
+    // marked already.
+    fn reach(&self, epoch: u32) -> bool {
+        if self.is(epoch) {
+            return false;
+        }
+        self.epoch.set(epoch);
+        true
+    }
+
+    fn is(&self, epoch: u32) -> bool {
+        self.epoch.get() == epoch
+    }
+}
+
+#[cfg(test)]
+use rust_os::{serial_print, serial_println};
+
+#[test_case]
+fn test_gc_collects_cycles() {
+    use crate::lang::run_script;
+    use crate::prelude;
+
+    serial_print!("test_gc_collects_cycles... ");
+    let before = allocator::heap_used();
+    let mut env = Environment::new();
+    prelude::install(&mut env);
+    // each call to `count` leaves behind a `go` that only it can reach
+    let text = "
+        fact n = if n <= 1 then 1 else n * fact (n - 1);
+        count n = { go k = if k == 0 then n else go (k - 1); go n };
+        total = fold (\\acc -> \\x -> acc + count x) 0 [1, 2, 3];
+        [gc [] > 0, gc [], fact 5, total]
+    ";
+    let val = run_script(text, &mut env).expect("vm failed");
+    assert_eq!(format!("{}", val), "[true, 0, 120, 6]");
+
+    // the functions still declared go with the script's runtime
+    drop(val);
+    drop(env);
+    assert_eq!(allocator::heap_used(), before);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_gc_collects_while_running() {
+    use crate::lang::run_script;
+    use crate::prelude;
+
+    serial_print!("test_gc_collects_while_running... ");
+    let mut env = Environment::new();
+    prelude::install(&mut env);
+    // every call leaves a `go` behind, far more of them than the heap can
+    // hold, so they have to be collected before the script ends; what the
+    // script still holds, on the stack, in a builtin running a callback or
+    // in a block, survives them
+    let text = format!("
+        loop n = {{ go k = k; if n == 0 then 0 else loop (n - 1) }};
+        keep = \\x -> x + 1;
+        [keep 1, map (\\x -> {{ f y = y * x; [loop 1000, f 2] }}) [1, 2], keep 2, gc [] < {}]
+    ", allocator::HEAP_SIZE / 2);
+    let val = run_script(&text, &mut env).expect("vm failed");
+    assert_eq!(format!("{}", val), "[2, [[0, 2], [0, 4]], 3, true]");
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_gc_roots_in_imports() {
+    use alloc::boxed::Box;
+    use rust_os::fs;
+    use crate::lang::run_script;
+    use crate::prelude;
+
+    serial_print!("test_gc_roots_in_imports... ");
+    let mut files = fs::MemoryFs::new();
+    files.write("collects", "freed = gc []; twice f x = f (f x)");
+    fs::mount(Box::new(files));
+
+    // the import collects while the script importing it waits, holding
+    // closures only it can reach
+    let mut env = Environment::new();
+    prelude::install(&mut env);
+    let text = r#"
+        offset = 10;
+        add x y = x + y + offset;
+        [inc, {twice}] = [add 1, import "collects"];
+        [inc 2, twice inc 0]
+    "#;
+    let val = run_script(text, &mut env).expect("vm failed");
+    assert_eq!(format!("{}", val), "[13, 22]");
+    fs::unmount();
+    serial_println!("[ok]");
+}
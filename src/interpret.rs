@@ -1,40 +1,450 @@
-use alloc::{string::String, collections::btree_map::BTreeMap};
-use crate::value::LangValue;
-use crate::ast::*;
-
-pub type Environment = BTreeMap<String, LangValue>;
-
-pub trait Executable {
-    fn exec(&self, mut env: &mut Environment);
-}
-pub trait Evaluatable {
-    fn eval(&self, env: &Environment) -> LangValue;
-}
-
-impl Evaluatable for Scope {
-  fn eval(&self, env: &Environment) -> LangValue {
-    let mut tmp_env = env.clone();
-    for i in 0..self.lines.len() {
-      let ret = match &self.lines[i] {
-        DeclOrExpr::Declaration(decl) => {
-          decl.exec(&mut tmp_env);
-          LangValue::LangNone
-        },
-        DeclOrExpr::Expression(expr) => {
-          expr.eval(&tmp_env)
+use alloc::{vec::Vec, string::String, rc::Rc, collections::BTreeSet};
+use core::{cmp, fmt, mem};
+use crate::value::{self, Builtin, LangValue, NativeFn, NativeFunc, TaskFn};
+use crate::scan::Span;
+use crate::gc;
+use crate::vm::{Next, Task};
+use crate::gc::Marker;
+use crate::scan::{TokenType, TokenType::*};
+
+pub type EvalResult = Result<LangValue, LangError>;
+
+// Bindings are kept in a persistent list, newest first. Declaring a name
+// pushes a node in front of the bindings it shadows, so blocks and closures
+// share everything bound before them by bumping a reference count instead
+// of copying it.
+#[derive(Clone)]
+pub struct Environment {
+    head: Option<Rc<Binding>>,
+}
+
+pub struct Binding {
+    name: Rc<str>,
+    pub value: LangValue,
+    pub next: Option<Rc<Binding>>,
+    pub mark: gc::Mark,
+}
+
+// An error raised while evaluating. `span` starts out where the error
+// happened and moves out to each call site as the error leaves a function,
+// so once it reaches the top it points into the source being run.
+#[derive(Debug)]
+pub struct LangError {
+    pub message: String,
+    pub span: Span,
+    // the calls the error escaped from, innermost first, as runs of the same
+    // call repeated back to back. Only the runs listed are kept, so an error
+    // from deep recursion fits in what's left of a full heap.
+    pub frames: Vec<Frame>,
+    // how many calls were left out of `frames`, between the innermost runs
+    // and the outermost
+    pub skipped: usize,
+}
+
+#[derive(Debug)]
+pub struct Frame {
+    // the function's declared name, if it has one
+    pub name: Option<String>,
+    // where in the function the error happened
+    pub span: Span,
+    // how many times the call repeats, like from a function calling itself
+    pub times: usize,
+}
+
+impl Environment {
+  pub fn new() -> Environment {
+    Environment { head: None }
+  }
+
+  pub fn get(&self, name: &str) -> Option<&LangValue> {
+    let mut node = &self.head;
+    while let Some(binding) = node {
+      if &*binding.name == name {
+        return Some(&binding.value);
+      }
+      node = &binding.next;
+    }
+    None
+  }
+
+  // the newest binding, for the garbage collector to trace from
+  pub fn head(&self) -> Option<&Rc<Binding>> {
+    self.head.as_ref()
+  }
+
+  // The bindings made on top of `base`, newest first, for an environment
+  // that was made by adding to it.
+  pub fn bindings_since(&self, base: &Environment) -> Vec<(&str, &LangValue)> {
+    let mut out: Vec<(&str, &LangValue)> = Vec::new();
+    let mut node = &self.head;
+    while let Some(binding) = node {
+      if let Some(base) = &base.head {
+        if Rc::ptr_eq(binding, base) {
+          break;
         }
+      }
+      out.push((&*binding.name, &binding.value));
+      node = &binding.next;
+    }
+    out
+  }
+
+  // Binds `name` in this environment only; copies of it made earlier, like
+  // the ones captured by closures, don't see the new binding.
+  pub fn insert(&mut self, name: Rc<str>, value: LangValue) {
+    let next = self.head.take();
+    self.head = Some(Rc::new(Binding { name, value, next, mark: gc::Mark::new() }));
+  }
+
+  // Drops the bindings shadowed by newer ones of the same name, so a value
+  // nothing can see any more gets freed. Copies made earlier keep the
+  // bindings they had. The nodes in front of the oldest shadowed binding are
+  // rebuilt, sharing what their values hold, and the rest stay shared.
+  pub fn compact(&mut self) {
+    let mut seen: BTreeSet<&str> = BTreeSet::new();
+    let mut shadowed: Vec<bool> = Vec::new();
+    let mut node = &self.head;
+    while let Some(binding) = node {
+      shadowed.push(!seen.insert(&*binding.name));
+      node = &binding.next;
+    }
+    let oldest = match shadowed.iter().rposition(|&shadowed| shadowed) {
+      Some(oldest) => oldest,
+      None => return,
+    };
+
+    let mut kept: Vec<Binding> = Vec::new();
+    let mut node = self.head.take();
+    for &shadowed in &shadowed[..=oldest] {
+      let binding = node.unwrap();
+      let mut binding = Rc::try_unwrap(binding).unwrap_or_else(|shared| Binding {
+        name: shared.name.clone(),
+        value: shared.value.clone(),
+        next: shared.next.clone(),
+        mark: gc::Mark::new(),
+      });
+      node = binding.next.take();
+      if !shadowed {
+        kept.push(binding);
+      }
+    }
+    while let Some(mut binding) = kept.pop() {
+      binding.next = node;
+      node = Some(Rc::new(binding));
+    }
+    self.head = node;
+  }
+
+  // Binds `name` to a builtin that runs once it has been applied to `arity`
+  // arguments. Builtins that need no input take one and ignore it.
+  pub fn register(&mut self, name: &str, arity: usize, func: NativeFn) {
+    self.register_builtin(name, arity, Builtin::Value(func));
+  }
+
+  // Like `register`, for a builtin that calls functions back.
+  pub fn register_task(&mut self, name: &str, arity: usize, func: TaskFn) {
+    self.register_builtin(name, arity, Builtin::Task(func));
+  }
+
+  fn register_builtin(&mut self, name: &str, arity: usize, func: Builtin) {
+    assert!(arity > 0, "Builtin {} must take an argument", name);
+    let func = Rc::new(NativeFunc { name: Rc::from(name), arity, func });
+    self.insert(Rc::from(name), LangValue::Native { func, args: Rc::from(Vec::new()) });
+  }
+}
+
+// Unlinks the nodes nobody else shares one at a time, like a list's `Pair`s.
+impl Drop for Environment {
+  fn drop(&mut self) {
+    let mut node = self.head.take();
+    while let Some(binding) = node {
+      node = match Rc::try_unwrap(binding) {
+        Ok(mut binding) => binding.next.take(),
+        Err(_) => break,
       };
-      if i == self.lines.len()-1 {
-        return ret;
+    }
+  }
+}
+
+impl LangError {
+  pub fn new(message: String, span: Span) -> LangError {
+    LangError {
+      message,
+      span,
+      frames: Vec::new(),
+      skipped: 0,
+    }
+  }
+
+  // Moves the error out of a call to `name` made at `call_span`.
+  pub fn unwind(mut self, name: Option<String>, call_span: Span) -> LangError {
+    match self.frames.last_mut() {
+      Some(last) if last.name == name && last.span == self.span => last.times += 1,
+      _ => self.frames.push(Frame { name, span: self.span, times: 1 }),
+    }
+    self.span = call_span;
+
+    // the run that's no longer among the outermost is left out
+    if self.frames.len() > MAX_FRAMES {
+      self.skipped += self.frames.remove(MAX_FRAMES / 2).times;
+    }
+    self
+  }
+}
+
+// The most runs of frames an error lists, half from each end, so the trace
+// of a deep recursion doesn't scroll the message off the screen.
+const MAX_FRAMES: usize = 6;
+
+impl fmt::Display for LangError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.message)?;
+    for (i, frame) in self.frames.iter().enumerate() {
+      if i == MAX_FRAMES / 2 && self.skipped > 0 {
+        write!(f, "\n  ... {} more calls", self.skipped)?;
+      }
+      match &frame.name {
+        Some(name) => write!(f, "\n  in {} on {}", name, frame.span)?,
+        None => write!(f, "\n  in an anonymous function on {}", frame.span)?,
       }
+      if frame.times > 1 {
+        write!(f, " ({} times)", frame.times)?;
+      }
+    }
+    Ok(())
+  }
+}
+
+// Applies an operator that always needs both of its operands; `span` covers
+// the whole operation.
+pub fn binary(oper: TokenType, left: LangValue, right: LangValue, span: Span) -> EvalResult {
+  use LangValue::*;
+  match oper {
+    EqualEqual => return Ok(LangBool(left == right)),
+    NotEqual => return Ok(LangBool(left != right)),
+    Less | LessEq | Greater | GreaterEq => return compare(oper, left, right, span),
+    PlusPlus => return concat(left, right, span),
+    _ => (),
+  }
+
+  if let (LangInt(x), LangInt(y)) = (&left, &right) {
+    if oper == Slash && *y == 0 {
+      return Err(LangError::new(String::from("Division by zero"), span));
+    }
+    let exact = match oper {
+      Plus => x.checked_add(*y),
+      Minus => x.checked_sub(*y),
+      Star => x.checked_mul(*y),
+      // division stays exact only when nothing is left over
+      Slash if x.checked_rem(*y) == Some(0) => x.checked_div(*y),
+      _ => None,
     };
-    LangValue::LangNone
+    if let Some(z) = exact {
+      return Ok(LangInt(z));
+    }
+  }
+
+  // anything an integer can't hold exactly is done in floats
+  let (x, y) = match (as_float(&left), as_float(&right)) {
+    (Some(x), Some(y)) => (x, y),
+    (Some(_), None) => return Err(LangError::new(format!("NaN {}", right), span)),
+    (None, _) => return Err(LangError::new(format!("NaN {}", left), span)),
+  };
+  Ok(LangNumber(match oper {
+    Plus => x + y,
+    Minus => x - y,
+    Star => x * y,
+    Slash => x / y,
+    _ => return Err(LangError::new(format!("Cannot eval {:?}", oper), span)),
+  }))
+}
+
+fn as_float(val: &LangValue) -> Option<f64> {
+  match val {
+    LangValue::LangInt(x) => Some(*x as f64),
+    LangValue::LangNumber(x) => Some(*x),
+    _ => None,
+  }
+}
+
+// Numbers compare by value, and chars and strings by their Unicode scalar values.
+fn compare(oper: TokenType, left: LangValue, right: LangValue, span: Span) -> EvalResult {
+  use LangValue::*;
+  let ordering = match (&left, &right) {
+    (LangInt(x), LangInt(y)) => Some(x.cmp(y)),
+    (LangInt(x), LangNumber(y)) => value::compare_int_float(*x, *y),
+    (LangNumber(x), LangInt(y)) => value::compare_int_float(*y, *x).map(cmp::Ordering::reverse),
+    (LangNumber(x), LangNumber(y)) => x.partial_cmp(y),
+    (LangString(x), LangString(y)) => Some(x.cmp(y)),
+    (LangChar(x), LangChar(y)) => Some(x.cmp(y)),
+    _ => return Err(LangError::new(format!("Cannot compare {} and {}", left, right), span)),
+  };
+  // NaN is unordered, so every comparison with it is false
+  Ok(LangBool(match ordering {
+    None => false,
+    Some(ordering) => match oper {
+      Less => ordering == cmp::Ordering::Less,
+      LessEq => ordering != cmp::Ordering::Greater,
+      Greater => ordering == cmp::Ordering::Greater,
+      _ => ordering != cmp::Ordering::Less,
+    },
+  }))
+}
+
+// `++` joins two strings or two lists.
+fn concat(left: LangValue, right: LangValue, span: Span) -> EvalResult {
+  use LangValue::*;
+  match (left, right) {
+    (LangString(x), LangString(y)) => Ok(LangString(Rc::from([&*x, &*y].concat()))),
+    (left @ LangPair(_), right @ LangPair(_)) | (left @ LangPair(_), right @ LangNone) |
+    (left @ LangNone, right @ LangPair(_)) | (left @ LangNone, right @ LangNone) => {
+      // the new list shares `right` rather than copying it
+      let mut items: Vec<LangValue> = Vec::new();
+      let mut rest = &left;
+      while let LangPair(pair) = rest {
+        items.push(pair.left.clone());
+        rest = &pair.right;
+      }
+
+      let mut out = right;
+      while let Some(item) = items.pop() {
+        out = LangValue::pair(item, out);
+      }
+      Ok(out)
+    },
+    (left, right) => Err(LangError::new(format!("Cannot concatenate {} and {}", left, right), span)),
+  }
+}
+
+pub fn unary(oper: TokenType, operand: LangValue, span: Span) -> EvalResult {
+  use LangValue::*;
+  match (oper, operand) {
+    (Minus, LangInt(x)) => Ok(x.checked_neg().map_or(LangNumber(-(x as f64)), LangInt)),
+    (Minus, LangNumber(x)) => Ok(LangNumber(-x)),
+    (Minus, other) => Err(LangError::new(format!("NaN {}", other), span)),
+    (Bang, LangBool(x)) => Ok(LangBool(!x)),
+    (Bang, other) => Err(LangError::new(format!("Expecting a boolean, not {}", other), span)),
+    (_, _) => Err(LangError::new(format!("Cannot eval {:?}", oper), span)),
+  }
+}
+
+// Applies `func` to every item of a LangPair list, making a new list.
+pub struct MapList {
+  func: LangValue,
+  // the items not yet given to `func`
+  rest: LangValue,
+  done: Vec<LangValue>,
+  span: Span,
+}
+
+impl MapList {
+  pub fn new(func: LangValue, list: LangValue, span: Span) -> MapList {
+    MapList { func, rest: list, done: Vec::new(), span }
   }
 }
 
-impl Executable for Decl {
-  fn exec(&self, mut env: &mut Environment) {
-    let val = self.right.eval(&env);
-    self.left.destruct(&mut env, val)
+impl Task for MapList {
+  fn step(&mut self, result: Option<LangValue>) -> Result<Next, LangError> {
+    use LangValue::*;
+    if let Some(val) = result {
+      self.done.push(val);
+    }
+    match mem::replace(&mut self.rest, LangNone) {
+      LangPair(pair) => {
+        self.rest = pair.right.clone();
+        Ok(Next::Call(self.func.clone(), pair.left.clone()))
+      },
+      LangNone => Ok(Next::Done(LangValue::list(mem::take(&mut self.done)))),
+      other => Err(LangError::new(format!("Cannot map over {}", other), self.span)),
+    }
+  }
+
+  fn trace(&self, marker: &mut Marker) {
+    marker.value(&self.func);
+    marker.value(&self.rest);
+    marker.values(&self.done);
   }
 }
+
+#[cfg(test)]
+use rust_os::{serial_print, serial_println};
+
+#[test_case]
+fn test_eval_lexical_scope() {
+  use crate::lang::run_script;
+
+  serial_print!("test_eval_lexical_scope... ");
+  let text = "a = 1; b = a; f x = a + x; c = { a = 10; f a }; [a, b, c]";
+  let expected = LangValue::list(vec![LangValue::LangInt(1), LangValue::LangInt(1), LangValue::LangInt(11)]);
+  assert_eq!(run_script(text, &mut Environment::new()).expect("vm failed"), expected);
+
+  let text = "x = { y = 1; y };\ny";
+  let err = run_script(text, &mut Environment::new()).expect_err("vm succeeded");
+  assert_eq!(err.message, "Unbound identifier `y` on line 2");
+  serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_eval_shares_environment() {
+  use crate::lang::run_script;
+  use rust_os::allocator::heap_used;
+
+  serial_print!("test_eval_shares_environment... ");
+  let text = "
+    { { { big } } };
+    id = \\x -> x;
+    [id, \\x -> { x }, \\x -> id x, \\[x] -> x, \\_ -> big, \\x -> x, \\x -> x, \\x -> x]
+  ";
+
+  // copying this into every block and closure would take far more than the
+  // whole heap
+  let mut env = Environment::new();
+  env.insert(Rc::from("big"), LangValue::LangString(Rc::from("x".repeat(16 * 1024))));
+
+  let before = heap_used();
+  let val = run_script(text, &mut env.clone()).expect("vm failed");
+  assert!(heap_used() - before < 8 * 1024);
+  drop(val);
+  assert_eq!(heap_used(), before);
+
+  // redeclaring a name lets go of the value it shadowed
+  env.insert(Rc::from("data"), LangValue::LangString(Rc::from("x".repeat(16 * 1024))));
+  env.insert(Rc::from("data"), LangValue::LangNone);
+  env.compact();
+  assert!(heap_used() - before < 1024);
+  assert_eq!(env.get("data"), Some(&LangValue::LangNone));
+  assert!(env.get("big").is_some());
+
+  // and rebuilding the bindings a closure still shares doesn't copy them
+  env.insert(Rc::from("text"), LangValue::LangString(Rc::from("x".repeat(16 * 1024))));
+  let captured = env.clone();
+  let before = heap_used();
+  env.insert(Rc::from("big"), LangValue::LangNone);
+  env.compact();
+  assert!(heap_used() - before < 1024);
+  assert_eq!(env.get("big"), Some(&LangValue::LangNone));
+  assert!(captured.get("big").is_some());
+  serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_eval_integers() {
+  use crate::lang::run_script;
+
+  serial_print!("test_eval_integers... ");
+  let text = "
+    [0xff + 0b1_01, 9007199254740993 - 1, 9223372036854775807 + 1, 0x8000_0000_0000_0000,
+      7 / 2, 6 / 2, 1.0 / 0, 2 * 1.5, 1 == 1.0, 2 < 2.5, (-9223372036854775807 - 1) / -1,
+      0xffff_ffff_ffff_ffff, 9007199254740993 == 9007199254740992.0, 9007199254740993 > 9007199254740992.0,
+      -1.5 < -1, 0xffff_ffff_ffff_fff1 == 0xffff_ffff_ffff_ffff, 0x7fff_ffff_ffff_ffff - 0x7fff_ffff_ffff_fffe,
+      -9223372036854775808, -9223372036854775808 == 0x8000_0000_0000_0000]
+  ";
+  let val = run_script(text, &mut Environment::new()).expect("vm failed");
+  assert_eq!(format!("{}", val), "[260, 9007199254740992, 9.223372036854776e18, \
+    -9223372036854775808, 3.5, 3, inf, 3.0, true, true, 9.223372036854776e18, \
+    -1, false, true, true, false, 1, -9223372036854775808, true]");
+
+  let err = run_script("x = 0; 1 / x", &mut Environment::new()).expect_err("vm succeeded");
+  assert_eq!(err.message, "Division by zero");
+  serial_println!("[ok]");
+}
@@ -0,0 +1,235 @@
+use core::cell::UnsafeCell;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use futures_core::stream::Stream;
+use lazy_static::lazy_static;
+use pc_keyboard::{Keyboard, ScancodeSet1, DecodedKey, layouts};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+const QUEUE_SIZE: usize = 128;
+
+// A single-producer, single-consumer ring buffer of raw scancodes. The
+// keyboard interrupt is the only producer, so pushing never blocks or
+// allocates; one slot is kept empty to tell a full queue from an empty one.
+pub struct ScancodeQueue {
+    buffer: UnsafeCell<[u8; QUEUE_SIZE]>,
+    // next slot to read, only written by the consumer
+    head: AtomicUsize,
+    // next slot to write, only written by the producer
+    tail: AtomicUsize,
+}
+
+unsafe impl Sync for ScancodeQueue {}
+
+impl ScancodeQueue {
+    pub const fn new() -> ScancodeQueue {
+        ScancodeQueue {
+            buffer: UnsafeCell::new([0; QUEUE_SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    // Returns false, dropping the scancode, if the queue is full.
+    pub fn push(&self, scancode: u8) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let next = (tail + 1) % QUEUE_SIZE;
+        if next == self.head.load(Ordering::Acquire) {
+            return false;
+        }
+        unsafe { (*self.buffer.get())[tail] = scancode };
+        self.tail.store(next, Ordering::Release);
+        true
+    }
+
+    pub fn pop(&self) -> Option<u8> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let scancode = unsafe { (*self.buffer.get())[head] };
+        self.head.store((head + 1) % QUEUE_SIZE, Ordering::Release);
+        Some(scancode)
+    }
+}
+
+static SCANCODES: ScancodeQueue = ScancodeQueue::new();
+
+// The task waiting for a key. It's only touched with interrupts off, so on
+// this single core the handler can never find it half updated, and nothing
+// ever waits for a lock in the handler.
+struct WakerSlot {
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+unsafe impl Sync for WakerSlot {}
+
+impl WakerSlot {
+    const fn new() -> WakerSlot {
+        WakerSlot { waker: UnsafeCell::new(None) }
+    }
+
+    fn register(&self, waker: &Waker) {
+        interrupts::without_interrupts(|| {
+            let slot = unsafe { &mut *self.waker.get() };
+            match slot {
+                Some(old) if old.will_wake(waker) => (),
+                _ => *slot = Some(waker.clone()),
+            }
+        });
+    }
+
+    fn take(&self) -> Option<Waker> {
+        interrupts::without_interrupts(|| unsafe { (*self.waker.get()).take() })
+    }
+
+    fn wake(&self) {
+        if let Some(waker) = self.take() {
+            waker.wake();
+        }
+    }
+}
+
+static WAKER: WakerSlot = WakerSlot::new();
+
+lazy_static! {
+    static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
+        Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1));
+}
+
+/// Queues a scancode read by the keyboard interrupt handler.
+pub(crate) fn add_scancode(scancode: u8) {
+    // a full queue means nobody is reading, so dropping keys is fine
+    SCANCODES.push(scancode);
+    WAKER.wake();
+}
+
+/// Returns the next key press, if one is waiting.
+pub fn try_read_key() -> Option<DecodedKey> {
+    let mut keyboard = KEYBOARD.lock();
+    while let Some(scancode) = SCANCODES.pop() {
+        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+            if let Some(key) = keyboard.process_keyevent(key_event) {
+                return Some(key);
+            }
+        }
+    }
+    None
+}
+
+/// Waits for the next key press, halting the CPU in between interrupts.
+pub fn read_key() -> DecodedKey {
+    loop {
+        // with interrupts off, a key can't arrive between the check and
+        // `hlt`; `sti` only takes effect after the instruction following it
+        interrupts::disable();
+        if let Some(key) = try_read_key() {
+            interrupts::enable();
+            return key;
+        }
+        unsafe { asm!("sti; hlt" :::: "volatile") };
+    }
+}
+
+/// An endless stream of key presses for async code.
+pub struct KeyStream {
+    _private: (),
+}
+
+impl KeyStream {
+    pub fn new() -> KeyStream {
+        KeyStream { _private: () }
+    }
+}
+
+impl Stream for KeyStream {
+    type Item = DecodedKey;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<DecodedKey>> {
+        // the waker goes in before checking, so a key that arrives while
+        // checking still wakes the task
+        WAKER.register(cx.waker());
+
+        match try_read_key() {
+            Some(key) => {
+                WAKER.take();
+                Poll::Ready(Some(key))
+            },
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[test_case]
+fn test_scancode_queue_order() {
+    serial_print!("test_scancode_queue_order... ");
+    let queue = ScancodeQueue::new();
+    assert_eq!(queue.pop(), None);
+    for scancode in 1..=3 {
+        assert!(queue.push(scancode));
+    }
+    assert_eq!(queue.pop(), Some(1));
+    assert_eq!(queue.pop(), Some(2));
+    assert!(queue.push(4));
+    assert_eq!(queue.pop(), Some(3));
+    assert_eq!(queue.pop(), Some(4));
+    assert_eq!(queue.pop(), None);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_scancode_queue_full() {
+    serial_print!("test_scancode_queue_full... ");
+    let queue = ScancodeQueue::new();
+    for i in 0..QUEUE_SIZE - 1 {
+        assert!(queue.push(i as u8));
+    }
+    assert!(!queue.push(0xff));
+    assert_eq!(queue.pop(), Some(0));
+    assert!(queue.push(0xff));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_key_stream_wakes() {
+    use core::ptr;
+    use core::task::{RawWaker, RawWakerVTable};
+
+    serial_print!("test_key_stream_wakes... ");
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, noop);
+    fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(ptr::null(), &VTABLE)
+    }
+    fn wake(_: *const ()) {
+        WAKES.fetch_add(1, Ordering::SeqCst);
+    }
+    fn noop(_: *const ()) {}
+
+    let waker = unsafe { Waker::from_raw(clone(ptr::null())) };
+    let mut cx = Context::from_waker(&waker);
+    let mut stream = KeyStream::new();
+    while try_read_key().is_some() {}
+
+    // nothing queued, so the waker is left for the interrupt handler
+    assert_eq!(Pin::new(&mut stream).poll_next(&mut cx), Poll::Pending);
+
+    // pressing `a` wakes the task once, and polling again returns the key;
+    // the real handler mustn't push at the same time, the queue only takes
+    // one producer
+    interrupts::without_interrupts(|| add_scancode(0x1e));
+    assert_eq!(WAKES.load(Ordering::SeqCst), 1);
+    assert_eq!(Pin::new(&mut stream).poll_next(&mut cx), Poll::Ready(Some(DecodedKey::Unicode('a'))));
+    assert!(WAKER.take().is_none());
+
+    // a key already queued is returned without a wakeup
+    interrupts::without_interrupts(|| add_scancode(0x30));
+    assert_eq!(Pin::new(&mut stream).poll_next(&mut cx), Poll::Ready(Some(DecodedKey::Unicode('b'))));
+    assert_eq!(WAKES.load(Ordering::SeqCst), 1);
+    serial_println!("[ok]");
+}
@@ -1,68 +1,63 @@
-// use alloc::{vec::Vec, string::String, boxed::Box};
-use alloc::{boxed::Box};
-use crate::ast::*;
-use crate::value::LangValue;
+use alloc::{string::String, rc::Rc};
 use crate::println;
-use crate::interpret;
-use crate::interpret::Evaluatable;
-
-pub fn test_interpreter() {
-    let env = interpret::Environment::new();
+use rust_os::print;
+use crate::scan;
+use crate::parse::{self, TokenIter};
+use crate::interpret::{Environment, LangError};
+#[cfg(test)]
+use crate::interpret::EvalResult;
+use crate::compile::{self, Chunk};
+use crate::value::LangValue;
+use crate::vm::{self, Runtime};
 
-    let ast = Scope{
-        lines: vec![
-            DeclOrExpr::Expression(Box::new(LangValue::LangNumber(7.0)) as Box<dyn Expr>),
-        ],
+// Scans, parses and runs `source` in `env`, printing the result or the
+// first error. A line that fails part-way leaves `env` as it was.
+pub fn run(source: &String, env: &mut Environment, runtime: &mut Runtime) {
+    let chunk = match compile(source) {
+        Ok(chunk) => chunk,
+        Err(e) => {
+            scan::report_error(source, e.span, &format!("{}", e));
+            return;
+        },
     };
 
-    println!("{}", ast.eval(&env));
-//   let text = String::from(r##"
-// $ print println getch = {
-//   [ch, get] = getch nil;
-//   listenInput print println getch line;
-// }
-// "##);
-//   let mut tokens: Vec<Token> = Vec::new();
-//   ScannerIter::init(&text).scan(&mut tokens);
+    let mut new_env = env.clone();
+    match vm::run(chunk, &mut new_env, runtime) {
+        Ok(val) => {
+            *env = new_env;
+            // the shell keeps this environment forever, so it mustn't hold
+            // on to values that have been redeclared
+            env.compact();
+            if val != LangValue::LangNone {
+                println!("{}", val);
+            }
+        },
+        Err(e) => scan::report_error(source, e.span, &format!("{}", e)),
+    }
 
-//   let mut token_iter = crate::parser::TokenIter::from(tokens);
-//   match crate::parser::parse_file(&mut token_iter) {
-//     Ok(ast) => {
-//       for decl in ast {
-//         println!("{}", decl.repr());
-//       }
-//     },
-//     Err(e) => {
-//       println!("{}", e);
-//       let tok = token_iter.prev();
-//       println!("Instead, I got {} on line {}", tok.literal, tok.line);
-//       let lines: Vec<&str> = text.split("\n").collect();
-//       println!("{}", lines[tok.line])
-//     }
-//   }
+    // collect between lines too, rather than waiting for someone to call `gc`
+    runtime.collect_if_full(env);
 }
 
-// $ print println getch = {
-//   listenInput print println getch line;
-// }
+// Runs `text` in `env` on the VM, with a runtime of its own, for tests.
+#[cfg(test)]
+pub fn run_script(text: &str, env: &mut Environment) -> EvalResult {
+    vm::run(compile(&String::from(text))?, env, &mut Runtime::new())
+}
 
-// listenInput print println getch line = {
-//   [ch, getch'] = getch nil;
-//   processInput ch line (
-//     \[line', env'] -> listenInput print println getch' line' env'
-//   );
-// }
+// Prints the bytecode `source` compiles to.
+pub fn disassemble(source: &String) {
+    match compile(source) {
+        Ok(chunk) => print!("{}", chunk),
+        Err(e) => scan::report_error(source, e.span, &format!("{}", e)),
+    }
+}
 
-// processInput ch line env callback ===++
-//   case ch of
-//     'n' -> {
-//       println "";
-//       [env', val] = (env-eval env line !);
-//       println val;
-//       print "# ";
-//       callback "" env';
-//     }
-//     _ -> {
-//       print ch;
-//       callback (line ++ ch) env;
-//     };
+// Scans, parses and compiles `source` for the VM. Every stage's errors come
+// out as a LangError, so they're reported like errors from running it.
+pub fn compile(source: &String) -> Result<Rc<Chunk>, LangError> {
+    let tokens = scan::scan(source).map_err(|e| LangError::new(e.message, e.span))?;
+    let ast = parse::parse_file(&mut TokenIter::from(tokens))
+        .map_err(|e| LangError::new(format!("{}", e), e.token.span))?;
+    compile::compile_script(&ast)
+}
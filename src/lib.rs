@@ -1,6 +1,7 @@
 #![no_std]
 #![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
+#![feature(asm)]
 
 #![cfg_attr(test, no_main)]
 #![feature(custom_test_frameworks)]
@@ -14,13 +15,15 @@ pub mod gdt;
 pub mod serial;
 pub mod vga_buffer;
 pub mod interrupts;
+pub mod keyboard;
+pub mod fs;
 pub mod memory;
 
 use core::panic::PanicInfo;
-use linked_list_allocator::LockedHeap;
+use allocator::CountingHeap;
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: CountingHeap = CountingHeap::empty();
 
 
 #[alloc_error_handler]
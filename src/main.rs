@@ -14,9 +14,16 @@ use core::panic::PanicInfo;
 use rust_os::println;
 
 pub mod lang;
+pub mod shell;
 pub mod scan;
 pub mod parse;
 pub mod interpret;
+pub mod compile;
+pub mod vm;
+pub mod gc;
+pub mod module;
+pub mod scripts;
+pub mod prelude;
 pub mod ast;
 pub mod value;
 
@@ -37,12 +44,11 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
 
-    lang::test_interpreter();
-
     #[cfg(test)]
     test_main();
 
-    rust_os::hlt_loop()
+    scripts::mount();
+    shell::run()
 }
 
 
@@ -0,0 +1,141 @@
+use alloc::{collections::BTreeMap, rc::Rc, string::String, vec::Vec};
+use rust_os::fs;
+use crate::gc::Marker;
+use crate::interpret::{Environment, EvalResult, LangError};
+use crate::lang;
+use crate::prelude;
+use crate::scan::Span;
+use crate::value::LangValue;
+use crate::vm::{self, Runtime};
+
+// `import "path"` runs the file at `path` from the mounted filesystem in an
+// environment of its own, and evaluates to a record of what it bound at the
+// top level. Each runtime only runs a file once, and importing it again
+// gives the same record, until another filesystem is mounted.
+
+// The files one runtime has imported.
+pub struct Modules {
+    loaded: BTreeMap<String, LangValue>,
+    // the files being run, outermost first, to catch imports going in a cycle
+    loading: Vec<String>,
+    // the filesystem generation `loaded` was read from
+    generation: usize,
+}
+
+impl Modules {
+    pub fn new() -> Modules {
+        Modules {
+            loaded: BTreeMap::new(),
+            loading: Vec::new(),
+            generation: fs::generation(),
+        }
+    }
+
+    // Marks the imported modules, which later imports hand out again.
+    pub fn trace(&self, marker: &mut Marker) {
+        marker.values(self.loaded.values());
+    }
+}
+
+// Imports the file at `path` for an `import` at `span`. Errors in the file
+// come out through the import, like errors in a function through its call.
+pub fn import(path: &str, span: Span, runtime: &mut Runtime) -> EvalResult {
+    let modules = &mut runtime.modules;
+    // a different filesystem may have different files at the same paths
+    if modules.generation != fs::generation() {
+        modules.loaded.clear();
+        modules.generation = fs::generation();
+    }
+    if let Some(module) = modules.loaded.get(path) {
+        return Ok(module.clone());
+    }
+    if modules.loading.iter().any(|other| other == path) {
+        let mut cycle = modules.loading.clone();
+        cycle.push(String::from(path));
+        return Err(LangError::new(format!("Import cycle {}", cycle.join(" -> ")), span));
+    }
+    let source = fs::read(path)
+        .map_err(|err| LangError::new(format!("Cannot import {:?}: {}", path, err), span))?;
+
+    modules.loading.push(String::from(path));
+    let module = run(&source, runtime);
+    runtime.modules.loading.pop();
+
+    let module = module.map_err(|err| err.unwind(Some(String::from(path)), span))?;
+    runtime.modules.loaded.insert(String::from(path), module.clone());
+    Ok(module)
+}
+
+fn run(source: &String, runtime: &mut Runtime) -> EvalResult {
+    let chunk = lang::compile(source)?;
+
+    let mut base = Environment::new();
+    prelude::install(&mut base);
+    let mut env = base.clone();
+    vm::run(chunk, &mut env, runtime)?;
+
+    // a name bound twice is exported as its newest binding
+    let mut fields: BTreeMap<String, LangValue> = BTreeMap::new();
+    for (name, value) in env.bindings_since(&base) {
+        fields.entry(String::from(name)).or_insert_with(|| value.clone());
+    }
+    Ok(LangValue::LangRecord(Rc::new(fields)))
+}
+
+#[cfg(test)]
+use rust_os::{serial_print, serial_println};
+
+#[test_case]
+fn test_module_import() {
+    use alloc::boxed::Box;
+    use crate::lang::run_script;
+
+    serial_print!("test_module_import... ");
+    let mut files = fs::MemoryFs::new();
+    files.write("math", "add x y = x + y; helper x = add 2 x; two = 1; two = 2");
+    files.write("main", r#"answer = { {helper, two} = import "math"; helper 40 + two }"#);
+    files.write("a", r#"b = import "b""#);
+    files.write("b", r#"a = import "a""#);
+    files.write("broken", "x = 1;\ny = x + true");
+    files.write("calls-g", "f x = g x");
+    fs::mount(Box::new(files));
+
+    let run_text = |text| run_script(text, &mut Environment::new());
+
+    let val = run_text(r#"[import "main", import "math" == import "math"]"#).expect("vm failed");
+    assert_eq!(format!("{}", val), "[{answer: 44}, true]");
+
+    // declaring a function after importing one under a name that was
+    // declared before doesn't link it into the imported one
+    let text = r#"f x = 0; {f} = import "calls-g"; g x = 99; f 1"#;
+    let err = run_text(text).expect_err("vm succeeded");
+    assert_eq!(err.message, "Unbound identifier `g` on line 1");
+
+    let err = run_text(r#"import "a""#).expect_err("vm succeeded");
+    assert_eq!(err.message, "Import cycle a -> b -> a");
+    assert_eq!(err.frames.len(), 2);
+
+    let err = run_text(r#"import "broken""#).expect_err("vm succeeded");
+    assert_eq!(err.message, "NaN true");
+    assert_eq!(err.frames[0].name, Some(String::from("broken")));
+    assert_eq!(err.frames[0].span.line, 2);
+
+    let err = run_text(r#"import "missing""#).expect_err("vm succeeded");
+    assert_eq!(err.message, r#"Cannot import "missing": no such file"#);
+
+    // mounting another filesystem replaces what was imported from the old one
+    let span = Span { line: 1, column: 1, end_line: 1, end_column: 1, start: 0, end: 0 };
+    let mut runtime = Runtime::new();
+    let val = import("math", span, &mut runtime).expect("import failed");
+    assert_eq!(format!("{}", val), "{add: <fn add>, helper: <fn helper>, two: 2}");
+    let mut files = fs::MemoryFs::new();
+    files.write("math", "two = 3");
+    fs::mount(Box::new(files));
+    let val = import("math", span, &mut runtime).expect("import failed");
+    assert_eq!(format!("{}", val), "{two: 3}");
+
+    fs::unmount();
+    let err = run_text(r#"import "math""#).expect_err("vm succeeded");
+    assert_eq!(err.message, r#"Cannot import "math": no filesystem is mounted"#);
+    serial_println!("[ok]");
+}
@@ -1,324 +1,865 @@
-// // pub trait Parser {
-// //     fn visit(&mut self, v: &mut dyn Visitor) -> VResult;
-// // }
-
-// use alloc::{boxed::Box, vec::Vec, string::{String, ToString}};
-// use core::result::{Result, Result::{Ok, Err}};
-// use crate::interpreter::{Token, TokenType, TokenType::*};
-// use crate::ast::*;
-// use crate::ast::LangValue::*;
-
-// type BoxedParserRes<T> = Result<Box<T>, &'static str>;
-// type ParserRes<T> = Result<T, &'static str>;
-
-
-// pub fn parse_file(mut tokens: &mut TokenIter) -> ParserRes<Vec<Decl>> {
-//     let mut decls: Vec<Decl> = Vec::new();
-//     loop {
-//         if tokens.at_end() {
-//             return Ok(decls);
-//         }
-
-//         let decl = parse_declaration(&mut tokens)?;
-//         decls.push(decl);
-//     }
-// }
-
-// fn parse_expr_or_decl(mut tokens: &mut TokenIter) -> ParserRes<ExprOrDecl> {
-//     // we know it's a function if it's only LiteralIdentifiers followed by an equals sign
-//     tokens.bookmark();
-//     let mut is_decl = true;
-//     loop {
-//         match tokens.next().kind {
-//             LiteralIdentifier => (),
-//             Equal => break,
-//             _ => {
-//                 is_decl = false;
-//                 break;
-//             },
-//         };
-//     }
-//     tokens.revert();
-
-//     if is_decl {
-//         Ok(ExprOrDecl::Declaration(parse_declaration(&mut tokens)?))
-//     } else {
-//         Ok(ExprOrDecl::Expression(parse_statement(&mut tokens)?))
-//     }
-// }
-
-// fn parse_assignment(mut tokens: &mut TokenIter) -> ParserRes<Decl> {
-//     let name = tokens.next().literal.clone();
-//     let mut params: Vec<String> = Vec::new();
-
-//     loop {
-//         let next = tokens.next();
-//         match next.kind {
-//             Equal => break,
-//             LiteralIdentifier => params.push(next.literal.clone()),
-//             _ => return Err("Expecting '=' or identifier."),
-//         }
-//     };
-
-//     let body = parse_statement(&mut tokens)?;
-
-//     Ok(Decl{
-//         name, params, body,
-//     })
-// }
-
-// fn parse_declaration(mut tokens: &mut TokenIter) -> ParserRes<Decl> {
-//     let name = tokens.next().literal.clone();
-//     let mut params: Vec<String> = Vec::new();
-
-//     loop {
-//         let next = tokens.next();
-//         match next.kind {
-//             Equal => break,
-//             LiteralIdentifier => params.push(next.literal.clone()),
-//             _ => return Err("Expecting '=' or identifier."),
-//         }
-//     };
-
-//     let body = parse_statement(&mut tokens)?;
-
-//     Ok(Decl{
-//         name, params, body,
-//     })
-// }
-
-// fn parse_statement(mut tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
-//     match parse_fn_call(&mut tokens) {
-//         Err(_) => parse_expr(&mut tokens),
-//         Ok(x) => Ok(x),
-//     }
-// }
-
-// fn parse_fn_call(mut tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
-//     if !tokens.matches(LiteralIdentifier) {
-//         return parse_expr(&mut tokens);
-//     }
-
-//     tokens.bookmark();
-
-//     let name = tokens.next().literal.clone();
-//     let mut args: Vec<Box<dyn Expr>> = Vec::new();
-
-//     match tokens.peek().kind {
-//         LiteralIdentifier | LiteralString | LiteralNumber |
-//         LeftParen | LeftCurlyBrace | LeftSquareBrace => {
-//             loop {
-//                 let expr = parse_expr(&mut tokens)?;
-//                 args.push(expr);
-
-//                 match tokens.peek().kind {
-//                     Semicolon => {
-//                         tokens.next();
-//                         break;
-//                     },
-//                     RightParen => {
-//                         break;
-//                     },
-//                     _ => (),
-//                 }
-//             }
-//         },
-//         _ => {
-//             tokens.revert();
-//             return parse_expr(&mut tokens);
-//         },
-//     }
-
-//     Ok(Box::new(FnCall{name, args}))
-// }
-
-// fn parse_expr(mut tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
-//     parse_block(&mut tokens)
-// }
-
-// pub fn parse_block(mut tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
-//     if !tokens.matches(LeftCurlyBrace) {
-//         return parse_parens(&mut tokens);
-//     }
-//     tokens.next();
-
-//     let mut items: Vec<ExprOrDecl> = Vec::new();
-//     loop {
-//         if tokens.matches(RightCurlyBrace) {
-//             tokens.next();
-//             return Ok(Box::new(Block{items}));
-//         }
-//         let expr_or_decl = parse_expr_or_decl(&mut tokens)?;
-//         items.push(expr_or_decl);
-//     }
-// }
-
-
-// fn parse_parens(mut tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
-//     if !tokens.matches(LeftParen) {
-//         if tokens.matches(LeftSquareBrace) {
-//             return parse_list(&mut tokens, parse_expr);
-//         } else {
-//             return parse_addition(&mut tokens);
-//         }
-//     }
-
-//     tokens.next();
-
-//     let out = parse_fn_call(&mut tokens)?;
-
-//     tokens.expect(RightParen);
-
-//     Ok(out)
-// }
-
-// fn parse_list(
-//     mut tokens: &mut TokenIter,
-//     subparser: fn(&mut TokenIter) -> BoxedParserRes<dyn Expr>,
-// ) -> BoxedParserRes<dyn Expr> {
-
-//     if !tokens.matches(LeftSquareBrace) {
-//         return Err("Expected [");
-//     }
-
-//     tokens.next();
-
-//     let mut items: Vec<Box<dyn Expr>> = Vec::new();
-//     items.push(subparser(&mut tokens)?);
-//     loop {
-//         match tokens.next().kind {
-//             Comma => {
-//                 items.push(parse_expr(&mut tokens)?)
-//             },
-//             RightSquareBrace => {
-//                 let mut out = Box::new(LangNone) as Box<dyn Expr>;
-//                 while items.len() > 0 {
-//                     out = Box::new(List{
-//                         left: items.pop().expect("end?!"),
-//                         right: out,
-//                     });
-//                 }
-//                 return Ok(out);
-//             },
-//             _ => return Err("Expecting , or ]"),
-//         }
-//     }
-// }
-
-// fn parse_addition(tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
-//     binary_parser(tokens, parse_mult, &[Plus, Minus])
-// }
-
-// fn parse_mult(tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
-//     binary_parser(tokens, parse_unary, &[Star, Slash])
-// }
-
-// fn parse_unary(tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
-//     // FIXME: implement unary!
-//     parse_single_token(tokens.next())
-// }
-
-// fn parse_single_token(token: Token) -> BoxedParserRes<dyn Expr> {
-//     match token.kind {
-//         LiteralIdentifier =>
-//             Ok(Box::new(Identifier{name: token.literal.clone()}) as Box<dyn Expr>),
-//         LiteralNumber => 
-//             Ok(Box::new(LangNumber(
-//                 token.literal.clone().parse::<f64>().unwrap()
-//             )) as Box<dyn Expr>),
-//         LiteralString =>
-//             Ok(Box::new(LangString(
-//                 token.literal.clone()[1..token.literal.len()-1].to_string()
-//                 )) as Box<dyn Expr>),
-//         _ => Err("Expecting single token."),
-//     }
-// }
-
-// fn parse_identifier(tokens: &mut TokenIter)  -> BoxedParserRes<dyn Expr> {
-//     let token = tokens.next();
-//     match token.kind {
-//         LiteralIdentifier =>
-//             Ok(Box::new(Identifier{name: token.literal.clone()}) as Box<dyn Expr>),
-//         _ => Err("Expecting identifier."),
-//     }
-// }
-
-// fn binary_parser(
-//             mut tokens: &mut TokenIter,
-//             sub_parser: fn(&mut TokenIter) -> BoxedParserRes<dyn Expr>,
-//             opers: &[TokenType]) -> BoxedParserRes<dyn Expr> {
-
-//     let mut expr = sub_parser(&mut tokens)?;
-
-//     loop {
-//         if tokens.at_end() {
-//             return Ok(expr);
-//         }
-
-//         let op_token = tokens.peek();
-
-//         let mut found = false;
-//         for op in opers.iter() {
-//             if op_token.kind == *op {
-//                 found = true;
-//                 let oper = tokens.next().kind;
-//                 let right = sub_parser(&mut tokens)?;
-//                 expr = Box::new(BinaryExpr{
-//                     oper, left: expr, right,
-//                 });
-//                 break;
-//             }
-//         }
-//         if !found {
-//             return Ok(expr);
-//         }
-//     }
-// }
-
-// pub struct TokenIter {
-//     tokens: Vec<Token>,
-//     index: usize,
-//     bookmark: usize,
-// }
-// impl TokenIter {
-//     pub fn from(vec: Vec<Token>) -> TokenIter {
-//         TokenIter {
-//             tokens: vec,
-//             index: 0,
-//             bookmark: 0,
-//         }
-//     }
-//     fn peek(&self) -> Token {
-//         if self.index >= self.tokens.len() {
-//             panic!("Unexpected EOF.");
-//         }
-//         self.tokens[self.index].clone()
-//     }
-//     fn next(&mut self) -> Token {
-//         let val = self.peek();
-//         self.index += 1;
-//         val
-//     }
-//     fn bookmark(&mut self) {
-//         self.bookmark = self.index;
-//     }
-//     fn revert(&mut self) {
-//         self.index = self.bookmark;
-//     }
-//     fn matches(&self, kind: TokenType) -> bool {
-//         let reality = self.peek().kind;
-//         reality == kind
-//     }
-//     fn expect(&mut self, kind: TokenType) {
-//         if !self.matches(kind) {
-//             panic!("Expecting {:?}", kind);
-//         }
-//         self.index += 1;
-//     }
-//     fn at_end(&self) -> bool {
-//         self.index >= self.tokens.len()
-//     }
-//     pub fn prev(&mut self) -> Token {
-//         self.tokens[self.index-1].clone()
-//     }
-// }
+use alloc::{boxed::Box, vec::Vec, string::{String, ToString}, rc::Rc};
+use core::fmt;
+use crate::scan::{Span, Token, TokenType, TokenType::*};
+use crate::ast::*;
+use crate::value::{LangValue, LangValue::*};
+
+type BoxedParserRes<T> = Result<Box<T>, ParseError>;
+type ParserRes<T> = Result<T, ParseError>;
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub message: String,
+    pub token: Token,
+}
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.token.kind {
+            Eof => write!(f, "{}, but reached the end of the input", self.message),
+            _ => write!(f, "{}, but got {:?}", self.message, self.token.literal),
+        }
+    }
+}
+
+pub fn parse_file(mut tokens: &mut TokenIter) -> ParserRes<Scope> {
+    let start = tokens.peek().span;
+    let lines = parse_lines(&mut tokens, Eof)?;
+    let end = tokens.expect(Eof, "Expecting end of file")?.span;
+    Ok(Scope{lines, span: start.to(end)})
+}
+
+// parses `;`-separated lines until (but not including) the closing token
+fn parse_lines(mut tokens: &mut TokenIter, closing: TokenType) -> ParserRes<Vec<DeclOrExpr>> {
+    let mut lines: Vec<DeclOrExpr> = Vec::new();
+    loop {
+        if tokens.matches(closing) {
+            return Ok(lines);
+        }
+
+        lines.push(parse_decl_or_expr(&mut tokens)?);
+
+        if tokens.matches(Semicolon) {
+            tokens.next();
+        } else if !tokens.matches(closing) {
+            return Err(tokens.error("Expecting ;"));
+        }
+    }
+}
+
+fn parse_decl_or_expr(mut tokens: &mut TokenIter) -> ParserRes<DeclOrExpr> {
+    // we know it's a declaration if it's only patterns followed by an equals sign,
+    // which mustn't be inside braces since `{ a = 1; a }` is a block
+    tokens.bookmark();
+    let mut is_decl = true;
+    let mut braces = 0;
+    loop {
+        match tokens.next().kind {
+            LiteralIdentifier | Underscore |
+            LiteralInteger | LiteralNumber | LiteralString | LiteralChar | True | False |
+            LeftSquareBrace | HashSquareBrace | RightSquareBrace | Comma | Pipe | Colon => (),
+            LeftCurlyBrace => braces += 1,
+            RightCurlyBrace if braces > 0 => braces -= 1,
+            Equal if braces == 0 => break,
+            _ => {
+                is_decl = false;
+                break;
+            },
+        };
+    }
+    tokens.revert();
+
+    if is_decl {
+        Ok(DeclOrExpr::Declaration(parse_declaration(&mut tokens)?))
+    } else {
+        Ok(DeclOrExpr::Expression(parse_expr(&mut tokens)?))
+    }
+}
+
+// `f x y = body` is sugar for `f = \x -> \y -> body`, where every lambda
+// knows the name so errors can say where they came from, and the outermost
+// one binds it so that `f` can recurse
+fn parse_declaration(mut tokens: &mut TokenIter) -> ParserRes<Decl> {
+    if !tokens.matches(LiteralIdentifier) {
+        let left = parse_pattern(&mut tokens)?;
+        tokens.expect(Equal, "Expecting =")?;
+        let right = parse_expr(&mut tokens)?;
+        return Ok(Decl{left: Rc::from(left), right});
+    }
+
+    let left = parse_identifier(&mut tokens)?;
+    let mut params: Vec<Box<dyn Destructure>> = Vec::new();
+    while !tokens.matches(Equal) {
+        params.push(parse_pattern(&mut tokens)?);
+    }
+    tokens.expect(Equal, "Expecting =")?;
+
+    let mut right = parse_expr(&mut tokens)?;
+    while let Some(param) = params.pop() {
+        let span = param.span().to(right.span());
+        right = Box::new(Lambda{
+            name: Some(left.name.clone()),
+            binds_self: params.is_empty(),
+            param: Rc::from(param),
+            body: Rc::from(right),
+            span,
+        });
+    }
+
+    Ok(Decl{left: Rc::new(left), right})
+}
+
+fn parse_expr(mut tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
+    parse_pipe(&mut tokens)
+}
+
+// x |> f |> g == g (f x)
+fn parse_pipe(tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
+    binary_parser(tokens, parse_compose, &[PipeForwards])
+}
+
+// f >> g >> h == \x -> h (g (f x))
+fn parse_compose(tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
+    binary_parser(tokens, parse_comprehension, &[ComposeForwards])
+}
+
+// {body} for {pattern} in {list} == map (\{pattern} -> {body}) {list}
+fn parse_comprehension(mut tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
+    let mut expr = parse_or(&mut tokens)?;
+
+    while tokens.matches(For) {
+        tokens.next();
+        let param = parse_pattern(&mut tokens)?;
+        tokens.expect(In, "Expecting in")?;
+        let list = parse_or(&mut tokens)?;
+        let span = expr.span().to(list.span());
+        expr = map_over(param, expr, list, span);
+    }
+
+    Ok(expr)
+}
+
+// for {pattern} in {list} {body}
+fn parse_for(mut tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
+    let start = tokens.expect(For, "Expecting for")?.span;
+    let param = parse_pattern(&mut tokens)?;
+    tokens.expect(In, "Expecting in")?;
+    let list = parse_primary(&mut tokens)?;
+    let body = parse_primary(&mut tokens)?;
+    let span = start.to(body.span());
+    Ok(map_over(param, body, list, span))
+}
+
+fn map_over(
+            param: Box<dyn Destructure>,
+            body: Box<dyn Expr>,
+            list: Box<dyn Expr>,
+            span: Span) -> Box<dyn Expr> {
+
+    let func = Box::new(Lambda{
+        name: None,
+        binds_self: false,
+        span: param.span().to(body.span()),
+        param: Rc::from(param),
+        body: Rc::from(body),
+    });
+    Box::new(Map{func, list, span})
+}
+
+// `a || b` and `a && b` only evaluate `b` when `a` doesn't decide the result
+fn parse_or(tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
+    binary_parser(tokens, parse_and, &[Or])
+}
+
+fn parse_and(tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
+    binary_parser(tokens, parse_comparison, &[And])
+}
+
+fn parse_comparison(tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
+    binary_parser(tokens, parse_concat, &[EqualEqual, NotEqual, Less, LessEq, Greater, GreaterEq])
+}
+
+// "a" ++ "b", [1] ++ [2]
+fn parse_concat(tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
+    binary_parser(tokens, parse_addition, &[PlusPlus])
+}
+
+fn parse_addition(tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
+    binary_parser(tokens, parse_mult, &[Plus, Minus])
+}
+
+fn parse_mult(tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
+    binary_parser(tokens, parse_unary, &[Star, Slash])
+}
+
+// -x, !x; looser than application, so `-f x` is `-(f x)`
+fn parse_unary(mut tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
+    match tokens.peek().kind {
+        Minus | Bang => {
+            let token = tokens.next();
+            if token.kind == Minus {
+                if let Some(min) = parse_min_integer(&mut tokens, &token) {
+                    return Ok(min);
+                }
+            }
+            let operand = parse_unary(&mut tokens)?;
+            Ok(Box::new(UnaryExpr{
+                oper: token.kind,
+                span: token.span.to(operand.span()),
+                operand,
+            }))
+        },
+        _ => parse_application(&mut tokens),
+    }
+}
+
+// The smallest integer is one more than the largest negated, so the literal
+// after its `-` is too big to be an integer on its own.
+fn parse_min_integer(tokens: &mut TokenIter, minus: &Token) -> Option<Box<dyn Expr>> {
+    let token = tokens.peek();
+    if token.kind != LiteralInteger || token.literal.replace('_', "") != "9223372036854775808" {
+        return None;
+    }
+    let span = token.span;
+    tokens.bookmark();
+    tokens.next();
+    // `-9223372036854775808 x` would negate a call
+    if starts_primary(tokens.peek().kind) {
+        tokens.revert();
+        return None;
+    }
+    Some(Box::new(Literal{value: LangInt(i64::MIN), span: minus.span.to(span)}))
+}
+
+// function calls by juxtaposition: `f a b` is `(f a) b`
+fn parse_application(mut tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
+    let mut expr = parse_primary(&mut tokens)?;
+
+    while starts_primary(tokens.peek().kind) {
+        let arg = parse_primary(&mut tokens)?;
+        expr = Box::new(Call{func: expr, arg});
+    }
+
+    Ok(expr)
+}
+
+fn starts_primary(kind: TokenType) -> bool {
+    match kind {
+        LiteralIdentifier | LiteralString | LiteralChar | LiteralInteger | LiteralNumber | True | False |
+        LeftParen | LeftCurlyBrace | LeftSquareBrace | HashSquareBrace | Backslash | Backtick |
+        If | Case | Import => true,
+        _ => false,
+    }
+}
+
+fn parse_primary(mut tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
+    let kind = tokens.peek().kind;
+    match kind {
+        Backslash => parse_lambda(&mut tokens),
+        For => parse_for(&mut tokens),
+        If => parse_if(&mut tokens),
+        Case => parse_case(&mut tokens),
+        LeftCurlyBrace if starts_record(&mut tokens) => parse_record(&mut tokens),
+        LeftCurlyBrace => parse_block(&mut tokens),
+        LeftParen => parse_parens(&mut tokens),
+        LeftSquareBrace => parse_list(&mut tokens),
+        HashSquareBrace => parse_vector(&mut tokens),
+        Backtick => parse_template(&mut tokens),
+        Import => parse_import(&mut tokens),
+        _ => parse_single_token(tokens.next()),
+    }
+}
+
+// \{pattern} -> {body}
+fn parse_lambda(mut tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
+    let start = tokens.expect(Backslash, "Expecting \\")?.span;
+    let param = parse_pattern(&mut tokens)?;
+    tokens.expect(Arrow, "Expecting ->")?;
+    // stop at `|>` and `>>` so lambdas can sit in the middle of a pipeline
+    let body = parse_comprehension(&mut tokens)?;
+    Ok(Box::new(Lambda{
+        name: None,
+        binds_self: false,
+        param: Rc::from(param),
+        span: start.to(body.span()),
+        body: Rc::from(body),
+    }))
+}
+
+// if {cond} then {then_branch} else {else_branch}
+fn parse_if(mut tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
+    let start = tokens.expect(If, "Expecting if")?.span;
+    let cond = parse_expr(&mut tokens)?;
+    tokens.expect(Then, "Expecting then")?;
+    let then_branch = parse_expr(&mut tokens)?;
+    tokens.expect(Else, "Expecting else")?;
+    // like a lambda body, stop at `|>` and `>>`
+    let else_branch = parse_comprehension(&mut tokens)?;
+    Ok(Box::new(IfElse{
+        cond, then_branch,
+        span: start.to(else_branch.span()),
+        else_branch,
+    }))
+}
+
+// case {value} of {pattern} -> {body}, ...
+fn parse_case(mut tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
+    let start = tokens.expect(Case, "Expecting case")?.span;
+    let value = parse_expr(&mut tokens)?;
+    tokens.expect(Of, "Expecting of")?;
+
+    let mut arms: Vec<CaseArm> = Vec::new();
+    loop {
+        let pattern = parse_pattern(&mut tokens)?;
+        tokens.expect(Arrow, "Expecting ->")?;
+        let body = parse_comprehension(&mut tokens)?;
+        arms.push(CaseArm{pattern: Rc::from(pattern), body});
+
+        if !tokens.matches(Comma) {
+            break;
+        }
+        tokens.next();
+    }
+
+    let end = arms[arms.len()-1].body.span();
+    Ok(Box::new(CaseOf{value, arms, span: start.to(end)}))
+}
+
+// identifier, literal, `_`, `[pattern, ... | rest]`, `#[pattern, ... | rest]`
+// or `{key: pattern, ...}`
+fn parse_pattern(mut tokens: &mut TokenIter) -> BoxedParserRes<dyn Destructure> {
+    match tokens.peek().kind {
+        Underscore => return Ok(Box::new(Wildcard{span: tokens.next().span})),
+        LiteralInteger | LiteralNumber | LiteralString | LiteralChar | True | False => {
+            let token = tokens.next();
+            let span = token.span;
+            return Ok(Box::new(LiteralPattern{value: parse_literal(token)?, span}));
+        },
+        LeftCurlyBrace => return parse_record_pattern(&mut tokens),
+        LeftSquareBrace | HashSquareBrace => (),
+        _ => return Ok(Box::new(parse_identifier(&mut tokens)?)),
+    }
+
+    let start = tokens.next();
+    let mut items: Vec<Box<dyn Destructure>> = Vec::new();
+    let mut rest: Option<Box<dyn Destructure>> = None;
+    if !tokens.matches(RightSquareBrace) {
+        items.push(parse_pattern(&mut tokens)?);
+        while tokens.matches(Comma) {
+            tokens.next();
+            items.push(parse_pattern(&mut tokens)?);
+        }
+        if tokens.matches(Pipe) {
+            tokens.next();
+            rest = Some(parse_pattern(&mut tokens)?);
+        }
+    }
+    let span = start.span.to(tokens.expect(RightSquareBrace, "Expecting , | or ]")?.span);
+
+    if start.kind == HashSquareBrace {
+        Ok(Box::new(VectorPattern{items, rest, span}))
+    } else {
+        Ok(Box::new(ListPattern{items, rest, span}))
+    }
+}
+
+// `{key: pattern, ...}`, where `key` alone is short for `key: key`
+fn parse_record_pattern(mut tokens: &mut TokenIter) -> BoxedParserRes<dyn Destructure> {
+    let start = tokens.expect(LeftCurlyBrace, "Expecting {")?.span;
+    let mut fields: Vec<(String, Box<dyn Destructure>)> = Vec::new();
+    if !tokens.matches(RightCurlyBrace) {
+        loop {
+            let (kind, span) = (tokens.peek().kind, tokens.peek().span);
+            let key = parse_key(&mut tokens, &fields)?;
+            let pattern = if tokens.matches(Colon) || kind != LiteralIdentifier {
+                tokens.expect(Colon, "Expecting :")?;
+                parse_pattern(&mut tokens)?
+            } else {
+                Box::new(Identifier{name: Rc::from(key.as_str()), span})
+            };
+            fields.push((key, pattern));
+
+            if !tokens.matches(Comma) {
+                break;
+            }
+            tokens.next();
+        }
+    }
+    let end = tokens.expect(RightCurlyBrace, "Expecting , or }")?.span;
+    Ok(Box::new(RecordPattern{fields, span: start.to(end)}))
+}
+
+// A record key, written as an identifier or a string, which mustn't repeat
+// one of the `fields` before it.
+fn parse_key<T>(tokens: &mut TokenIter, fields: &[(String, T)]) -> ParserRes<String> {
+    let token = tokens.next();
+    let key = match token.kind {
+        LiteralIdentifier => token.literal.clone(),
+        LiteralString => unescape(&token, &token.literal[1..token.literal.len()-1])?,
+        _ => return Err(ParseError{
+            message: "Expecting a key".to_string(),
+            token,
+        }),
+    };
+    if fields.iter().any(|(other, _)| *other == key) {
+        return Err(ParseError{
+            message: "Duplicate key".to_string(),
+            token,
+        });
+    }
+    Ok(key)
+}
+
+fn parse_block(mut tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
+    let start = tokens.expect(LeftCurlyBrace, "Expecting {")?.span;
+    let lines = parse_lines(&mut tokens, RightCurlyBrace)?;
+    let end = tokens.expect(RightCurlyBrace, "Expecting }")?.span;
+    Ok(Box::new(Scope{lines, span: start.to(end)}))
+}
+
+// A `{` starts a record rather than a block if it's followed by `}` or `key:`.
+fn starts_record(tokens: &mut TokenIter) -> bool {
+    tokens.bookmark();
+    tokens.next();
+    let out = match tokens.next().kind {
+        RightCurlyBrace => true,
+        LiteralIdentifier | LiteralString => tokens.matches(Colon),
+        _ => false,
+    };
+    tokens.revert();
+    out
+}
+
+// {key: value, ...}
+fn parse_record(mut tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
+    let start = tokens.expect(LeftCurlyBrace, "Expecting {")?.span;
+    let mut fields: Vec<(String, Box<dyn Expr>)> = Vec::new();
+    if !tokens.matches(RightCurlyBrace) {
+        loop {
+            let key = parse_key(&mut tokens, &fields)?;
+            tokens.expect(Colon, "Expecting :")?;
+            fields.push((key, parse_expr(&mut tokens)?));
+
+            if !tokens.matches(Comma) {
+                break;
+            }
+            tokens.next();
+        }
+    }
+    let end = tokens.expect(RightCurlyBrace, "Expecting , or }")?.span;
+    Ok(Box::new(Record{fields, span: start.to(end)}))
+}
+
+fn parse_parens(mut tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
+    tokens.expect(LeftParen, "Expecting (")?;
+    let out = parse_expr(&mut tokens)?;
+    tokens.expect(RightParen, "Expecting )")?;
+    Ok(out)
+}
+
+fn parse_list(mut tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
+    let start = tokens.expect(LeftSquareBrace, "Expecting [")?.span;
+
+    let mut items: Vec<Box<dyn Expr>> = Vec::new();
+    if !tokens.matches(RightSquareBrace) {
+        items.push(parse_expr(&mut tokens)?);
+        while tokens.matches(Comma) {
+            tokens.next();
+            items.push(parse_expr(&mut tokens)?);
+        }
+    }
+    let end = tokens.expect(RightSquareBrace, "Expecting , or ]")?.span;
+
+    let mut out = Box::new(Literal{
+        value: LangNone,
+        span: if items.is_empty() { start.to(end) } else { end },
+    }) as Box<dyn Expr>;
+    while let Some(left) = items.pop() {
+        let span = if items.is_empty() { start.to(end) } else { left.span().to(end) };
+        out = Box::new(List{left, right: out, span});
+    }
+    Ok(out)
+}
+
+// import "{path}"
+fn parse_import(tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
+    let start = tokens.expect(Import, "Expecting import")?.span;
+    let token = tokens.expect(LiteralString, "Expecting a path")?;
+    let path = unescape(&token, &token.literal[1..token.literal.len()-1])?;
+    Ok(Box::new(ImportExpr{path, span: start.to(token.span)}))
+}
+
+// #[item, ...]
+fn parse_vector(mut tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
+    let start = tokens.expect(HashSquareBrace, "Expecting #[")?.span;
+    let mut items: Vec<Box<dyn Expr>> = Vec::new();
+    if !tokens.matches(RightSquareBrace) {
+        items.push(parse_expr(&mut tokens)?);
+        while tokens.matches(Comma) {
+            tokens.next();
+            items.push(parse_expr(&mut tokens)?);
+        }
+    }
+    let end = tokens.expect(RightSquareBrace, "Expecting , or ]")?.span;
+    Ok(Box::new(Vector{items, span: start.to(end)}))
+}
+
+// `text $name $(expr)`
+fn parse_template(mut tokens: &mut TokenIter) -> BoxedParserRes<dyn Expr> {
+    let start = tokens.expect(Backtick, "Expecting `")?.span;
+    let mut parts: Vec<TemplatePart> = Vec::new();
+    loop {
+        let token = tokens.next();
+        let part = match token.kind {
+            Backtick => return Ok(Box::new(Template{parts, span: start.to(token.span)})),
+            TemplateText => TemplatePart::Text(unescape(&token, &token.literal)?),
+            LiteralIdentifier => TemplatePart::Interpolation(Box::new(Identifier{
+                name: Rc::from(&token.literal[1..]),
+                span: token.span,
+            })),
+            Dollar => TemplatePart::Interpolation(parse_parens(&mut tokens)?),
+            _ => return Err(ParseError{
+                message: "Expecting end of template".to_string(),
+                token,
+            }),
+        };
+        parts.push(part);
+    }
+}
+
+// Decodes the escapes in `text`, which comes from a string, char or template
+// `token`: \n \t \r \0 \\ \" \' \` \$ and \u{hex}.
+fn unescape(token: &Token, text: &str) -> ParserRes<String> {
+    let error = |message: String| ParseError{message, token: token.clone()};
+
+    let mut out = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        out.push(match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some(c) if "\\\"'`$".contains(c) => c,
+            Some('u') => {
+                if chars.next() != Some('{') {
+                    return Err(error("Expecting { after \\u".to_string()));
+                }
+                let mut hex = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => hex.push(c),
+                        None => return Err(error("Expecting } after \\u{...".to_string())),
+                    }
+                }
+                match u32::from_str_radix(&hex, 16).ok().and_then(core::char::from_u32) {
+                    Some(c) => c,
+                    None => return Err(error(format!("Invalid unicode escape \\u{{{}}}", hex))),
+                }
+            },
+            Some(c) => return Err(error(format!("Unknown escape sequence \\{}", c))),
+            None => return Err(error("Expecting an escape sequence".to_string())),
+        });
+    }
+    Ok(out)
+}
+
+fn parse_single_token(token: Token) -> BoxedParserRes<dyn Expr> {
+    let span = token.span;
+    match token.kind {
+        LiteralIdentifier =>
+            Ok(Box::new(Identifier{name: Rc::from(token.literal), span}) as Box<dyn Expr>),
+        LiteralInteger | LiteralNumber | LiteralString | LiteralChar | True | False =>
+            Ok(Box::new(Literal{value: parse_literal(token)?, span}) as Box<dyn Expr>),
+        _ => Err(ParseError{
+            message: "Expecting an expression".to_string(),
+            token,
+        }),
+    }
+}
+
+fn parse_literal(token: Token) -> ParserRes<LangValue> {
+    match token.kind {
+        LiteralInteger => {
+            let literal: String = token.literal.chars().filter(|&c| c != '_').collect();
+            let (digits, radix) = match literal.get(..2) {
+                Some("0x") => (&literal[2..], 16),
+                Some("0b") => (&literal[2..], 2),
+                _ => (&literal[..], 10),
+            };
+            if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+                return Err(ParseError{
+                    message: "Expecting an integer".to_string(),
+                    token,
+                });
+            }
+            if radix != 10 {
+                // hex and binary spell out bits, so they're read as the 64-bit
+                // two's complement value they'd be in a register
+                return match u64::from_str_radix(digits, radix) {
+                    Ok(x) => Ok(LangInt(x as i64)),
+                    Err(_) => Err(ParseError{
+                        message: "Expecting at most 64 bits".to_string(),
+                        token,
+                    }),
+                };
+            }
+            match digits.parse::<i64>() {
+                Ok(x) => Ok(LangInt(x)),
+                // too big for an integer, so it's only kept approximately
+                Err(_) => Ok(LangNumber(digits.parse::<f64>().unwrap_or(f64::INFINITY))),
+            }
+        },
+        LiteralNumber => match token.literal.parse::<f64>() {
+            Ok(x) => Ok(LangNumber(x)),
+            Err(_) => Err(ParseError{
+                message: "Expecting a number".to_string(),
+                token,
+            }),
+        },
+        LiteralString => Ok(LangString(Rc::from(unescape(&token, &token.literal[1..token.literal.len()-1])?))),
+        LiteralChar => {
+            let text = unescape(&token, &token.literal[1..token.literal.len()-1])?;
+            let mut chars = text.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Ok(LangChar(c)),
+                _ => Err(ParseError{
+                    message: "Expecting a single character".to_string(),
+                    token,
+                }),
+            }
+        },
+        True => Ok(LangBool(true)),
+        False => Ok(LangBool(false)),
+        _ => Err(ParseError{
+            message: "Expecting a literal".to_string(),
+            token,
+        }),
+    }
+}
+
+fn parse_identifier(tokens: &mut TokenIter) -> ParserRes<Identifier> {
+    let token = tokens.next();
+    match token.kind {
+        LiteralIdentifier => Ok(Identifier{name: Rc::from(token.literal), span: token.span}),
+        _ => Err(ParseError{
+            message: "Expecting identifier".to_string(),
+            token,
+        }),
+    }
+}
+
+fn binary_parser(
+            mut tokens: &mut TokenIter,
+            sub_parser: fn(&mut TokenIter) -> BoxedParserRes<dyn Expr>,
+            opers: &[TokenType]) -> BoxedParserRes<dyn Expr> {
+
+    let mut expr = sub_parser(&mut tokens)?;
+
+    loop {
+        if !opers.contains(&tokens.peek().kind) {
+            return Ok(expr);
+        }
+
+        let oper = tokens.next().kind;
+        let right = sub_parser(&mut tokens)?;
+        expr = Box::new(BinaryExpr{
+            oper, left: expr, right,
+        });
+    }
+}
+
+pub struct TokenIter {
+    tokens: Vec<Token>,
+    index: usize,
+    bookmark: usize,
+}
+impl TokenIter {
+    // `vec` must end with an Eof token, as produced by `scan::scan`
+    pub fn from(vec: Vec<Token>) -> TokenIter {
+        TokenIter {
+            tokens: vec,
+            index: 0,
+            bookmark: 0,
+        }
+    }
+    fn peek(&self) -> &Token {
+        let last = self.tokens.len() - 1;
+        &self.tokens[core::cmp::min(self.index, last)]
+    }
+    fn next(&mut self) -> Token {
+        let val = self.peek().clone();
+        if val.kind != Eof {
+            self.index += 1;
+        }
+        val
+    }
+    fn bookmark(&mut self) {
+        self.bookmark = self.index;
+    }
+    fn revert(&mut self) {
+        self.index = self.bookmark;
+    }
+    fn matches(&self, kind: TokenType) -> bool {
+        self.peek().kind == kind
+    }
+    fn expect(&mut self, kind: TokenType, message: &str) -> ParserRes<Token> {
+        if !self.matches(kind) {
+            return Err(self.error(message));
+        }
+        Ok(self.next())
+    }
+    fn error(&self, message: &str) -> ParseError {
+        ParseError {
+            message: message.to_string(),
+            token: self.peek().clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+use rust_os::{serial_print, serial_println};
+
+// The source each line of `text` parses to, written out again.
+#[cfg(test)]
+fn show_lines(text: &str) -> Vec<String> {
+    use crate::scan;
+
+    let tokens = scan::scan(&String::from(text)).expect("scan failed");
+    let ast = parse_file(&mut TokenIter::from(tokens)).expect("parse failed");
+    ast.lines.iter().map(|line| format!("{}", line)).collect()
+}
+
+#[test_case]
+fn test_parse_arithmetic() {
+    serial_print!("test_parse_arithmetic... ");
+    let text = "a = 1; b = { c = 2; a + c * 3 }; (b - a) / 2";
+    assert_eq!(show_lines(text), [
+        "a =\n  1;",
+        "b =\n  {\n    c =\n      2;\n    (a + (c * 3))\n  };",
+        "((b - a) / 2)",
+    ]);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_parse_curried_declaration() {
+    serial_print!("test_parse_curried_declaration... ");
+    let text = "add x y = x + y; helper x = add 2 x; helper 3";
+    assert_eq!(show_lines(text), [
+        "add =\n  \\x ->\n    \\y ->\n      (x + y);",
+        "helper =\n  \\x ->\n    ((add 2) x);",
+        "(helper 3)",
+    ]);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_parse_destructuring_lambda() {
+    serial_print!("test_parse_destructuring_lambda... ");
+    let text = "sub = \\[x, [y]] -> x - y; sub [5, [2]]";
+    assert_eq!(show_lines(text), [
+        "sub =\n  \\[x, [y]] ->\n    (x - y);",
+        "(sub [5 [[2 nil] nil]])",
+    ]);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_parse_destructuring_declaration() {
+    serial_print!("test_parse_destructuring_declaration... ");
+    let text = "[a, _ | rest] = [1, 2, 3, 4]; [c, d] = rest; a + c + d";
+    assert_eq!(show_lines(text), [
+        "[a, _ | rest] =\n  [1 [2 [3 [4 nil]]]];",
+        "[c, d] =\n  rest;",
+        "((a + c) + d)",
+    ]);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_parse_pipeline() {
+    serial_print!("test_parse_pipeline... ");
+    // a lambda's body ends at the next `>>`
+    let text = "3 |> \\x -> [x, x + 1] >> \\[a, b] -> a * b >> double |> double";
+    assert_eq!(show_lines(text), [
+        "((3 |> ((\\x ->\n  [x [(x + 1) nil]] >> \\[a, b] ->\n  (a * b)) >> double)) |> double)",
+    ]);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_parse_for() {
+    serial_print!("test_parse_for... ");
+    let text = r"
+        x * 2 for x in [1, 2];
+        for [a, b] in [[1, 2], [3, 4]] {
+            a * b
+        };
+        \xs -> x * 2 for x in xs |> f
+    ";
+    assert_eq!(show_lines(text), [
+        "(map \\x ->\n  (x * 2) [1 [2 nil]])",
+        "(map \\[a, b] ->\n  {\n    (a * b)\n  } [[1 [2 nil]] [[3 [4 nil]] nil]])",
+        "(\\xs ->\n  (map \\x ->\n    (x * 2) xs) |> f)",
+    ]);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_parse_template() {
+    serial_print!("test_parse_template... ");
+    let text = r"`hello $user-name', $(`nested $(1 + 1)`) \$x`";
+    assert_eq!(show_lines(text), ["`hello $(user-name'), $(`nested $((1 + 1))`) $x`"]);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_parse_conditionals() {
+    serial_print!("test_parse_conditionals... ");
+    let text = r#"
+        case x of
+            [true, _] -> "first",
+            [_, "b" | rest] -> if false then "never" else "second",
+            _ -> "neither"
+    "#;
+    assert_eq!(show_lines(text), [
+        "case x of\n  [true, _] ->\n    \"first\",\n  [_, \"b\" | rest] ->\n    \
+            if false then\n      \"never\"\n    else\n      \"second\",\n  _ ->\n    \"neither\"",
+    ]);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_parse_operators() {
+    serial_print!("test_parse_operators... ");
+    let text = "a || b && -c * d + e ++ f == g < h";
+    assert_eq!(show_lines(text), ["(a || (b && ((((((-c) * d) + e) ++ f) == g) < h)))"]);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_parse_literal_errors() {
+    use crate::scan;
+
+    serial_print!("test_parse_literal_errors... ");
+    let errors = [
+        (r#""\u{41""#, "Expecting } after \\u{..."),
+        (r#""\u{110000}""#, "Invalid unicode escape \\u{110000}"),
+        (r#""\q""#, "Unknown escape sequence \\q"),
+        ("0x1_0000_0000_0000_0000", "Expecting at most 64 bits"),
+    ];
+    for (text, message) in errors.iter() {
+        let tokens = scan::scan(&String::from(*text)).expect("scan failed");
+        let err = parse_file(&mut TokenIter::from(tokens)).err().expect("parse succeeded");
+        assert_eq!(err.message, *message);
+    }
+    assert_eq!(show_lines(r#""\u{41}\u{1F600}""#), ["\"A😀\""]);
+    assert_eq!(show_lines("-9223372036854775808; -9223372036854775808 x"),
+        ["-9223372036854775808", "(-(9.223372036854776e18 x))"]);
+    serial_println!("[ok]");
+}
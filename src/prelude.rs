@@ -0,0 +1,369 @@
+use alloc::{boxed::Box, vec::Vec, string::{String, ToString}, rc::Rc};
+use rust_os::{print, println};
+use crate::gc::Marker;
+use crate::interpret::{Environment, EvalResult, LangError, MapList};
+use crate::scan::Span;
+use crate::value::LangValue::{self, *};
+use crate::vm::{Next, Task};
+
+// Registers the builtins every shell starts with.
+pub fn install(env: &mut Environment) {
+    env.register("print", 1, print);
+    env.register("println", 1, println);
+    env.register_task("map", 2, map);
+    env.register_task("filter", 2, filter);
+    env.register_task("fold", 3, fold);
+    env.register("len", 1, len);
+    env.register("head", 1, head);
+    env.register("tail", 1, tail);
+    env.register("chars", 1, chars);
+    env.register("split", 2, split);
+    env.register("join", 2, join);
+    env.register("trim", 1, trim);
+    env.register("to_upper", 1, to_upper);
+    env.register("get", 2, get);
+    env.register("keys", 1, keys);
+    env.register("values", 1, values);
+    env.register("insert", 3, insert);
+    env.register_task("gc", 1, gc);
+}
+
+// print x
+fn print(args: Vec<LangValue>, _span: Span) -> EvalResult {
+    print!("{}", args[0].show());
+    Ok(LangNone)
+}
+
+// println x
+fn println(args: Vec<LangValue>, _span: Span) -> EvalResult {
+    println!("{}", args[0].show());
+    Ok(LangNone)
+}
+
+// map f [a, b, ...] == [f a, f b, ...]
+fn map(mut args: Vec<LangValue>, span: Span) -> Result<Box<dyn Task>, LangError> {
+    let list = args.pop().unwrap();
+    Ok(Box::new(MapList::new(args.pop().unwrap(), list, span)))
+}
+
+// filter f xs == the items of xs for which f is true
+fn filter(mut args: Vec<LangValue>, span: Span) -> Result<Box<dyn Task>, LangError> {
+    let mut items = list_items(args.pop().unwrap(), span)?;
+    items.reverse();
+    Ok(Box::new(Filter { func: args.pop().unwrap(), items, kept: Vec::new(), span }))
+}
+
+struct Filter {
+    func: LangValue,
+    // the items left to check, last first
+    items: Vec<LangValue>,
+    kept: Vec<LangValue>,
+    span: Span,
+}
+
+impl Task for Filter {
+    fn step(&mut self, result: Option<LangValue>) -> Result<Next, LangError> {
+        match result {
+            // the item `func` was given is still the last one left
+            Some(LangBool(true)) => self.kept.push(self.items.pop().unwrap()),
+            Some(LangBool(false)) => {
+                self.items.pop();
+            },
+            Some(other) => return Err(LangError::new(
+                format!("Expecting filter to return a boolean, not {}", other), self.span)),
+            None => (),
+        }
+        Ok(match self.items.last() {
+            Some(item) => Next::Call(self.func.clone(), item.clone()),
+            None => Next::Done(LangValue::list(core::mem::take(&mut self.kept))),
+        })
+    }
+
+    fn trace(&self, marker: &mut Marker) {
+        marker.value(&self.func);
+        marker.values(self.items.iter().chain(self.kept.iter()));
+    }
+}
+
+// fold f acc [a, b] == f (f acc a) b
+fn fold(mut args: Vec<LangValue>, span: Span) -> Result<Box<dyn Task>, LangError> {
+    let mut items = list_items(args.pop().unwrap(), span)?;
+    items.reverse();
+    let acc = args.pop().unwrap();
+    Ok(Box::new(Fold { func: args.pop().unwrap(), items, acc: Some(acc), curried: false }))
+}
+
+struct Fold {
+    func: LangValue,
+    // the items left to fold in, last first
+    items: Vec<LangValue>,
+    acc: Option<LangValue>,
+    // whether the last call was `func acc`, so its result still needs the
+    // next item
+    curried: bool,
+}
+
+impl Task for Fold {
+    fn step(&mut self, result: Option<LangValue>) -> Result<Next, LangError> {
+        if let Some(val) = result {
+            if self.curried {
+                self.curried = false;
+                return Ok(Next::Call(val, self.items.pop().unwrap()));
+            }
+            self.acc = Some(val);
+        }
+        Ok(match self.items.last() {
+            Some(_) => {
+                self.curried = true;
+                Next::Call(self.func.clone(), self.acc.take().unwrap())
+            },
+            None => Next::Done(self.acc.take().unwrap()),
+        })
+    }
+
+    fn trace(&self, marker: &mut Marker) {
+        marker.value(&self.func);
+        marker.values(self.items.iter().chain(self.acc.iter()));
+    }
+}
+
+// the number of items in a list or vector, fields in a record, or
+// characters in a string
+fn len(mut args: Vec<LangValue>, span: Span) -> EvalResult {
+    match args.pop().unwrap() {
+        LangString(x) => Ok(LangInt(x.chars().count() as i64)),
+        LangVector(items) => Ok(LangInt(items.len() as i64)),
+        LangRecord(fields) => Ok(LangInt(fields.len() as i64)),
+        list => Ok(LangInt(list_items(list, span)?.len() as i64)),
+    }
+}
+
+fn head(mut args: Vec<LangValue>, span: Span) -> EvalResult {
+    match args.pop().unwrap() {
+        LangPair(pair) => Ok(pair.left.clone()),
+        other => Err(LangError::new(format!("Cannot take the head of {}", other), span)),
+    }
+}
+
+fn tail(mut args: Vec<LangValue>, span: Span) -> EvalResult {
+    match args.pop().unwrap() {
+        LangPair(pair) => Ok(pair.right.clone()),
+        other => Err(LangError::new(format!("Cannot take the tail of {}", other), span)),
+    }
+}
+
+// chars "héllo" == ['h', 'é', 'l', 'l', 'o']
+fn chars(mut args: Vec<LangValue>, span: Span) -> EvalResult {
+    let text = expect_string(args.pop().unwrap(), span)?;
+    Ok(LangValue::list(text.chars().map(LangChar).collect()))
+}
+
+// split ", " "a, b" == ["a", "b"]
+fn split(mut args: Vec<LangValue>, span: Span) -> EvalResult {
+    let text = expect_string(args.pop().unwrap(), span)?;
+    let sep = expect_string(args.pop().unwrap(), span)?;
+    if sep.is_empty() {
+        return Err(LangError::new("Cannot split on an empty string".to_string(), span));
+    }
+    Ok(LangValue::list(text.split(&*sep).map(|part| LangString(Rc::from(part))).collect()))
+}
+
+// join ", " ["a", 'b'] == "a, b"
+fn join(mut args: Vec<LangValue>, span: Span) -> EvalResult {
+    let list = args.pop().unwrap();
+    let sep = expect_string(args.pop().unwrap(), span)?;
+    let mut out = String::new();
+    for (i, item) in list_items(list, span)?.into_iter().enumerate() {
+        if i > 0 {
+            out.push_str(&sep);
+        }
+        match item {
+            LangString(x) => out.push_str(&x),
+            LangChar(x) => out.push(x),
+            other => return Err(LangError::new(
+                format!("Expecting a string or char to join, not {}", other), span)),
+        }
+    }
+    Ok(LangString(Rc::from(out)))
+}
+
+fn trim(mut args: Vec<LangValue>, span: Span) -> EvalResult {
+    let text = expect_string(args.pop().unwrap(), span)?;
+    Ok(LangString(Rc::from(text.trim())))
+}
+
+// to_upper "straße" == "STRASSE"
+fn to_upper(mut args: Vec<LangValue>, span: Span) -> EvalResult {
+    let text = expect_string(args.pop().unwrap(), span)?;
+    Ok(LangString(Rc::from(text.chars().flat_map(char::to_uppercase).collect::<String>())))
+}
+
+// get 1 #[a, b] == b, and get "name" {name: a} == a
+fn get(mut args: Vec<LangValue>, span: Span) -> EvalResult {
+    let collection = args.pop().unwrap();
+    let key = args.pop().unwrap();
+    match (&collection, &key) {
+        (LangVector(items), LangInt(i)) => match items.get(*i as usize) {
+            Some(item) if *i >= 0 => Ok(item.clone()),
+            _ => Err(LangError::new(
+                format!("Index {} is out of range for {} items", i, items.len()), span)),
+        },
+        (LangRecord(fields), LangString(name)) => match fields.get(&**name) {
+            Some(field) => Ok(field.clone()),
+            None => Err(LangError::new(format!("No key {} in {}", key, collection), span)),
+        },
+        _ => Err(LangError::new(format!("Cannot get {} from {}", key, collection), span)),
+    }
+}
+
+// the keys of a record as a list, in order
+fn keys(mut args: Vec<LangValue>, span: Span) -> EvalResult {
+    match args.pop().unwrap() {
+        LangRecord(fields) => Ok(LangValue::list(fields.keys().map(|key| LangString(Rc::from(&**key))).collect())),
+        other => Err(LangError::new(format!("Expecting a record, not {}", other), span)),
+    }
+}
+
+// the values of a record as a list, in the order of their keys
+fn values(mut args: Vec<LangValue>, span: Span) -> EvalResult {
+    match args.pop().unwrap() {
+        LangRecord(fields) => Ok(LangValue::list(fields.values().cloned().collect())),
+        other => Err(LangError::new(format!("Expecting a record, not {}", other), span)),
+    }
+}
+
+// A copy of a record with a field set, or of a vector with the item at an
+// index replaced; the index may be the length to add an item:
+//
+// insert "b" 2 {a: 1} == {a: 1, b: 2}
+// insert 2 c #[a, b] == #[a, b, c]
+fn insert(mut args: Vec<LangValue>, span: Span) -> EvalResult {
+    let collection = args.pop().unwrap();
+    let val = args.pop().unwrap();
+    let key = args.pop().unwrap();
+    match (collection, key) {
+        (LangVector(mut items), LangInt(i)) if i >= 0 && i as usize <= items.len() => {
+            // only copies the items if some other value still shares them
+            let items_mut = Rc::make_mut(&mut items);
+            match items_mut.get_mut(i as usize) {
+                Some(item) => *item = val,
+                None => items_mut.push(val),
+            }
+            Ok(LangVector(items))
+        },
+        (LangVector(items), LangInt(i)) => Err(LangError::new(
+            format!("Index {} is out of range for {} items", i, items.len()), span)),
+        (LangRecord(mut fields), LangString(name)) => {
+            Rc::make_mut(&mut fields).insert(name.to_string(), val);
+            Ok(LangRecord(fields))
+        },
+        (collection, key) => Err(LangError::new(
+            format!("Cannot insert {} into {}", key, collection), span)),
+    }
+}
+
+// gc [] collects garbage, returning how many bytes it reclaimed. It's run by
+// the VM, which knows everything a running script holds.
+fn gc(_args: Vec<LangValue>, _span: Span) -> Result<Box<dyn Task>, LangError> {
+    Ok(Box::new(Collect))
+}
+
+struct Collect;
+
+impl Task for Collect {
+    fn step(&mut self, result: Option<LangValue>) -> Result<Next, LangError> {
+        Ok(match result {
+            Some(freed) => Next::Done(freed),
+            None => Next::Collect,
+        })
+    }
+
+    fn trace(&self, _marker: &mut Marker) {}
+}
+
+fn expect_string(val: LangValue, span: Span) -> Result<Rc<str>, LangError> {
+    match val {
+        LangString(x) => Ok(x),
+        other => Err(LangError::new(format!("Expecting a string, not {}", other), span)),
+    }
+}
+
+fn list_items(list: LangValue, span: Span) -> Result<Vec<LangValue>, LangError> {
+    let mut items: Vec<LangValue> = Vec::new();
+    let mut rest = list;
+    loop {
+        rest = match rest {
+            LangPair(pair) => {
+                items.push(pair.left.clone());
+                pair.right.clone()
+            },
+            LangNone => return Ok(items),
+            other => return Err(LangError::new(format!("Expecting a list, not {}", other), span)),
+        };
+    }
+}
+
+#[cfg(test)]
+use rust_os::{serial_print, serial_println};
+
+#[test_case]
+fn test_prelude() {
+    use crate::lang::run_script;
+
+    serial_print!("test_prelude... ");
+    let text = r#"
+        sum = fold (\acc -> \x -> acc + x) 0;
+        big = filter (\x -> x > 1);
+        [sum (big [1, 2, 3]), len (map (\x -> x * 2) [1, 2]), len "héllo",
+            head [1, 2], tail [1, 2], tail [1]]
+    "#;
+    let mut env = Environment::new();
+    install(&mut env);
+    let val = run_script(text, &mut env).expect("vm failed");
+    assert_eq!(format!("{}", val), "[5, 2, 5, 1, [2], nil]");
+
+    let text = "head (tail [1])";
+    let err = run_script(text, &mut env).expect_err("vm succeeded");
+    assert_eq!(err.message, "Cannot take the head of nil");
+    assert_eq!(err.frames[0].name, Some(String::from("head")));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_prelude_strings() {
+    use crate::lang::run_script;
+
+    serial_print!("test_prelude_strings... ");
+    let text = r#"
+        [chars "h\u{e9}!", split ", " " a, b ", join "-" ["a", 'b', "\t"],
+            trim " \n x ", to_upper "straße", '\'' < 'a']
+    "#;
+    let mut env = Environment::new();
+    install(&mut env);
+    let val = run_script(text, &mut env).expect("vm failed");
+    assert_eq!(format!("{}", val),
+        r#"[['h', 'é', '!'], [" a", "b "], "a-b-\t", "x", "STRASSE", true]"#);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_prelude_collections() {
+    use crate::lang::run_script;
+
+    serial_print!("test_prelude_collections... ");
+    let text = r#"
+        v = #[1, 2];
+        r = {size: 3, "the name": `x`};
+        [get 1 v, insert 2 3 v, insert 0 0 v, v, len v,
+            get "the name" r, keys r, values r, insert "a" true r, len {}]
+    "#;
+    let mut env = Environment::new();
+    install(&mut env);
+    let val = run_script(text, &mut env).expect("vm failed");
+    assert_eq!(format!("{}", val), r#"[2, #[1, 2, 3], #[0, 2], #[1, 2], 2, "x", ["size", "the name"], [3, "x"], {a: true, size: 3, "the name": "x"}, 0]"#);
+
+    let text = "get 2 #[1, 2]";
+    let err = run_script(text, &mut env).expect_err("vm succeeded");
+    assert_eq!(err.message, "Index 2 is out of range for 2 items");
+    serial_println!("[ok]");
+}
@@ -1,11 +1,77 @@
 use alloc::{vec::Vec, string::String};
+use core::{cmp, fmt};
 use crate::println;
 
+// Lines and columns count from 1; `end_column` and `end` point one past the
+// last character, so an empty span has `column == end_column`. Every op a
+// chunk compiles to keeps one, so they're kept to 32 bits, far more than the
+// shell's sources need.
+#[derive(Debug)]
+#[derive(Copy)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub struct Span {
+  pub line: u32,
+  pub column: u32,
+  pub end_line: u32,
+  pub end_column: u32,
+  // byte offsets into the source
+  pub start: u32,
+  pub end: u32,
+}
+impl Span {
+  // the span covering both `self` and everything up to the end of `other`
+  pub fn to(self, other: Span) -> Span {
+    Span {
+      end_line: other.end_line,
+      end_column: other.end_column,
+      end: other.end,
+      ..self
+    }
+  }
+}
+impl fmt::Display for Span {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "line {}, column {}", self.line, self.column)
+  }
+}
+
+// Prints the offending line of `source` with carets under `span`.
+pub fn report_error(source: &str, span: Span, message: &str) {
+  let line = source.lines().nth(span.line as usize - 1).unwrap_or("");
+  let end_column = if span.end_line == span.line {
+    span.end_column as usize
+  } else {
+    line.chars().count() + 1
+  };
+
+  println!("Error on {}:", span);
+  println!("{}", line);
+  println!("{}{}",
+    " ".repeat(span.column as usize - 1),
+    "^".repeat(cmp::max(1, end_column.saturating_sub(span.column as usize))));
+  println!("{}", message);
+}
+
+// Whether `c` can appear in an identifier after its first letter.
+pub fn is_identifier_char(c: char) -> bool {
+  match c {
+    'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '\'' => true,
+    _ => false,
+  }
+}
+
+#[derive(Debug)]
+pub struct ScanError {
+  pub span: Span,
+  pub message: String,
+}
+
 #[derive(Debug)]
 #[derive(Clone)]
 pub struct Token {
   pub kind: TokenType,
-  pub line: usize,
+  pub span: Span,
   pub literal: String,
 }
 
@@ -21,10 +87,10 @@ pub enum TokenType {
   LeftParen, RightParen,
   LeftSquareBrace, RightSquareBrace,
   LeftCurlyBrace, RightCurlyBrace,
-  Colon, Pipe, Backslash,
+  Colon, Pipe, Backslash, Backtick,
   Plus, Minus, Star, Slash,
   Dollar, Semicolon, Comma,
-  Underscore,
+  Underscore, Bang,
 
 
   // One or two character tokens.
@@ -33,15 +99,22 @@ pub enum TokenType {
   Greater, GreaterEq,
   Less, LessEq,
   Arrow, // ->
+  PlusPlus, // ++
+  HashSquareBrace, // #[, opening a vector
   PipeForwards,  // |>
+  ComposeForwards,  // >>
 
   // Literals.
-  LiteralIdentifier, LiteralString, LiteralChar, LiteralNumber,
+  LiteralIdentifier, LiteralString, LiteralChar, LiteralInteger, LiteralNumber,
+  TemplateText, // the literal text between interpolations in a `template`
 
   // Keywords.
   True, False,
   If, Then, Else,
+  Case, Of,
   Let, In,
+  For,
+  Import,
   Yield,
 
   Comment,
@@ -49,12 +122,27 @@ pub enum TokenType {
   Eof,
 }
 
+pub fn scan(source: &String) -> Result<Vec<Token>, ScanError> {
+  let mut tokens: Vec<Token> = Vec::new();
+  ScannerIter::init(source).scan(&mut tokens)?;
+  Ok(tokens)
+}
+
 struct ScannerIter<'a> {
-  source: &'a String,
   iter: core::iter::Peekable<core::str::Chars<'a>>,
   next: Option<char>,
   buffer: String,
-  line: usize,
+  line: u32,
+  column: u32,
+  offset: u32,
+  start_line: u32,
+  start_column: u32,
+  start_offset: u32,
+  // whether we are in the text part of a `template`
+  in_template: bool,
+  // the paren depth at each open `$(` interpolation
+  interpolations: Vec<usize>,
+  parens: usize,
 }
 
 impl<'a> ScannerIter<'a> {
@@ -62,31 +150,73 @@ impl<'a> ScannerIter<'a> {
     let mut iter = source.chars().peekable();
     let next = iter.next();
     ScannerIter {
-      source, iter, next,
+      iter, next,
       buffer: String::from(""),
-      line: 0,
+      line: 1,
+      column: 1,
+      offset: 0,
+      start_line: 1,
+      start_column: 1,
+      start_offset: 0,
+      in_template: false,
+      interpolations: Vec::new(),
+      parens: 0,
     }
   }
 
-  fn scan(&mut self, tokens: &mut Vec<Token>) {
-    let error: Option<String> = None;
+  fn scan(&mut self, tokens: &mut Vec<Token>) -> Result<(), ScanError> {
+    let mut error: Option<ScanError> = None;
 
     use TokenType::*;
     loop {
-      let tok = match self.next() {
+      self.start_line = self.line;
+      self.start_column = self.column;
+      self.start_offset = self.offset;
+
+      let tok = if self.in_template {
+        match self.template_part() {
+          Ok(tok) => tok,
+          Err(err) => {
+            error = Some(err);
+            Unrecognized
+          },
+        }
+      } else { match self.next() {
         None => Eof,
         Some(c) => match c {
           '\n' | '\r' | '\t' | ' ' => Ignore,
 
-          '(' => LeftParen,
-          ')' => RightParen,
+          '(' => {
+            self.parens += 1;
+            LeftParen
+          },
+          ')' => {
+            self.parens = self.parens.saturating_sub(1);
+            if self.interpolations.last() == Some(&self.parens) {
+              self.interpolations.pop();
+              self.in_template = true;
+            }
+            RightParen
+          },
+          '`' => {
+            self.in_template = true;
+            Backtick
+          },
           '[' => LeftSquareBrace,
+          '#' => match self.peek() {
+            Some('[') => {self.advance(); HashSquareBrace},
+            _ => Unrecognized,
+          },
           ']' => RightSquareBrace,
           '{' => LeftCurlyBrace,
           '}' => RightCurlyBrace,
           ':' => Colon,
-          '+' => Plus,
+          '+' => match self.peek() {
+            Some('+') => {self.advance(); PlusPlus},
+            _ => Plus,
+          },
           '*' => Star,
+          '/' => Slash,
           ';' => Semicolon,
           ',' => Comma,
           '\\' => Backslash,
@@ -97,30 +227,48 @@ impl<'a> ScannerIter<'a> {
                   match self.next() {
                       Some(i) => match i {
                         '\\' => self.advance(),
-                        '"' => break,
+                        '"' => break LiteralString,
                         _ => (),
                       },
-                      None => break,
+                      None => {
+                        error = Some(self.error(String::from("Unterminated string literal")));
+                        break Unrecognized
+                      },
                   }
               }
-              LiteralString
           }
 
           '\'' => {
-            if self.next().expect("Char content expected") == '\\' {
-              self.advance();
+            if self.next() == Some('\\') {
+              // a `\u{...}` escape runs to its closing brace
+              if self.next() == Some('u') && self.peek() == Some('{') {
+                while let Some(c) = self.next() {
+                  if c == '}' {
+                    break;
+                  }
+                }
+              }
             }
 
-            let closing: char = self.next().expect("Closing single quote");
-            if closing != '\'' {
-              panic!("Expecting closing single quote, not {}", closing)
+            match self.next() {
+              Some('\'') => LiteralChar,
+              Some(closing) => {
+                error = Some(self.error(format!("Expecting closing single quote, not {:?}", closing)));
+                Unrecognized
+              },
+              None => {
+                error = Some(self.error(String::from("Expecting closing single quote")));
+                Unrecognized
+              },
             }
-
-            LiteralChar
           }
 
           '!' => match self.peek() {
             Some('=') => {self.advance(); NotEqual},
+            _ => Bang,
+          },
+          '&' => match self.peek() {
+            Some('&') => {self.advance(); And},
             _ => Unrecognized,
           },
           '=' => match self.peek() {
@@ -152,16 +300,29 @@ impl<'a> ScannerIter<'a> {
           }
           '>' => match self.peek() {
             Some('=') => {self.advance(); GreaterEq},
+            Some('>') => {self.advance(); ComposeForwards},
             _ => Greater,
           },
 
+          // `0x` and `0b` start hex and binary integers, like 0xb800_0000
+          '0' if self.peek() == Some('x') || self.peek() == Some('b') => {
+            self.advance();
+            while let Some('0'..='9') | Some('a'..='z') | Some('A'..='Z') | Some('_') = self.peek() {
+              self.advance();
+            }
+            LiteralInteger
+          },
+
           '0'..='9' => {
+            // digits without a decimal point make an integer
+            let mut kind = LiteralInteger;
             loop {
               match self.peek() {
                 None => break,
                 Some(c) => match c {
                   '0'..='9' => self.advance(),
                   '.' => {
+                    kind = LiteralNumber;
                     self.advance();
                     loop {
                       match self.peek() {
@@ -182,49 +343,128 @@ impl<'a> ScannerIter<'a> {
                 }
               }
             };
-            LiteralNumber
+            kind
           },
 
           'a'..='z' | 'A'..='Z' | '$' => {
               loop {
                   match self.peek() {
                       Some(i) => match i {
-                        'a'..='z'|'A'..='Z'|'0'..='9'|'-'|'_'|'\'' => self.advance(),
+                        c if is_identifier_char(c) => self.advance(),
                         _ => break,
                       },
                       None => break,
                   };
               };
-              LiteralIdentifier
+              match self.buffer.as_str() {
+                "true" => True,
+                "false" => False,
+                "if" => If,
+                "then" => Then,
+                "else" => Else,
+                "case" => Case,
+                "of" => Of,
+                "for" => For,
+                "in" => In,
+                "import" => Import,
+                _ => LiteralIdentifier,
+              }
           },
 
           _ => Unrecognized,
         },
-      };
+      }};
+      if tok == Unrecognized && error.is_none() {
+        error = Some(self.error(format!("Unrecognized token: {}", self.buffer)));
+      }
       match error {
         None => {
           match tok {
-            Unrecognized => panic!("Unrecognized token: {}", self.buffer),
-            Eof => break,
-            Ignore => self.buffer = String::from(""),
+            Eof => {
+              self.add_token(tokens, tok);
+              break
+            },
+            Ignore | Comment => self.buffer = String::from(""),
             _ => self.add_token(tokens, tok),
           }
         },
-        Some(err) => {
-          println!("Error on line {}:", self.line);
-          let lines: Vec<&str> = self.source.lines().collect();
-          println!("{}", lines[self.line]);
-          println!("\n{}", err);
-          break
-        },
+        Some(err) => return Err(err),
       }
     }
+    Ok(())
+  }
+
+  // Scans the next piece of a template: literal text, `$name`, the `$` of a
+  // `$(expr)`, or the closing backtick.
+  fn template_part(&mut self) -> Result<TokenType, ScanError> {
+    use TokenType::*;
+    match (self.peek(), self.peek_second()) {
+      (None, _) => Err(self.error(String::from("Unterminated template string"))),
+      (Some('`'), _) => {
+        self.advance();
+        self.in_template = false;
+        Ok(Backtick)
+      },
+      (Some('$'), Some('(')) => {
+        self.advance();
+        self.interpolations.push(self.parens);
+        self.in_template = false;
+        Ok(Dollar)
+      },
+      (Some('$'), Some(c)) if c.is_ascii_alphabetic() => {
+        self.advance();
+        loop {
+          match self.peek() {
+            Some(c) if is_identifier_char(c) => self.advance(),
+            _ => break Ok(LiteralIdentifier),
+          }
+        }
+      },
+      _ => {
+        loop {
+          if self.peek() == Some('\\') {
+            self.advance();
+          }
+          self.advance();
+          if self.at_template_boundary() {
+            break Ok(TemplateText)
+          }
+        }
+      },
+    }
+  }
+
+  fn at_template_boundary(&mut self) -> bool {
+    match (self.peek(), self.peek_second()) {
+      (None, _) | (Some('`'), _) | (Some('$'), Some('(')) => true,
+      (Some('$'), Some(c)) => c.is_ascii_alphabetic(),
+      _ => false,
+    }
+  }
+
+  fn error(&self, message: String) -> ScanError {
+    ScanError {
+      span: self.span(),
+      message,
+    }
+  }
+
+  // the span of the token currently in `buffer`
+  fn span(&self) -> Span {
+    Span {
+      line: self.start_line,
+      column: self.start_column,
+      end_line: self.line,
+      end_column: self.column,
+      start: self.start_offset,
+      end: self.offset,
+    }
   }
 
   fn add_token(&mut self, tokens: &mut Vec<Token>, kind: TokenType) {
     tokens.push(Token {
       kind,
-      line: self.line,
+      span: self.span(),
       literal: self.buffer.clone(),
     });
     self.buffer = String::from("")
@@ -236,8 +476,12 @@ impl<'a> ScannerIter<'a> {
       None => None,
       Some(c) => {
         self.buffer.push(c);
+        self.offset += c.len_utf8() as u32;
         if c == '\n' {
           self.line += 1;
+          self.column = 1;
+        } else {
+          self.column += 1;
         }
         self.next = self.iter.next();
         Some(c)
@@ -252,4 +496,20 @@ impl<'a> ScannerIter<'a> {
   fn peek(&self) -> Option<char> {
     self.next
   }
+
+  fn peek_second(&mut self) -> Option<char> {
+    self.iter.peek().cloned()
+  }
+}
+
+#[cfg(test)]
+use rust_os::{serial_print, serial_println};
+
+#[test_case]
+fn test_scan_error_location() {
+  serial_print!("test_scan_error_location... ");
+  let err = scan(&String::from("a = 1;\nb = 'xy';")).expect_err("scan succeeded");
+  assert_eq!((err.span.line, err.span.column), (2, 5));
+  assert_eq!((err.span.start, err.span.end), (11, 14));
+  serial_println!("[ok]");
 }
@@ -0,0 +1,52 @@
+use alloc::boxed::Box;
+use rust_os::fs::{self, MemoryFs};
+
+// The scripts built into the kernel, by the path `import` finds them at.
+const SCRIPTS: &[(&str, &str)] = &[
+    ("math", include_str!("../scripts/math.lang")),
+    ("lists", include_str!("../scripts/lists.lang")),
+];
+
+// Mounts a filesystem holding the bundled scripts, so the shell has
+// something to import.
+pub fn mount() {
+    let mut files = MemoryFs::new();
+    for (path, source) in SCRIPTS.iter() {
+        files.write(path, source);
+    }
+    fs::mount(Box::new(files));
+}
+
+#[cfg(test)]
+use rust_os::{serial_print, serial_println};
+
+#[test_case]
+fn test_scripts_import() {
+    use crate::interpret::Environment;
+    use crate::lang::run_script;
+
+    serial_print!("test_scripts_import... ");
+    mount();
+    let text = r#"
+        {abs, max, pow, sum} = import "math";
+        {range, reverse, take, drop, zip, any, all} = import "lists";
+        xs = range 1 5;
+        [abs (0 - 3), max 2 7, pow 2 10, sum xs, reverse xs, take 2 xs, drop 3 xs,
+            zip xs [`a`, `b`], any (\x -> x > 3) xs, all (\x -> x > 3) xs]
+    "#;
+    let val = run_script(text, &mut Environment::new()).expect("vm failed");
+    assert_eq!(format!("{}", val),
+        r#"[3, 7, 1024, 10, [4, 3, 2, 1], [1, 2], [4], [[1, "a"], [2, "b"]], true, false]"#);
+
+    // lists longer than the heap has room to recurse over an item at a time
+    let text = r#"
+        {pow, sum} = import "math";
+        {range, take, drop, zip} = import "lists";
+        ys = range 0 200;
+        [sum ys, sum (take 150 ys), zip (drop 198 ys) ys, pow 1 200, pow 2 (-1), pow 2 0.5]
+    "#;
+    let val = run_script(text, &mut Environment::new()).expect("vm failed");
+    assert_eq!(format!("{}", val), "[19900, 11175, [[198, 0], [199, 1]], 1, 1, 2]");
+    fs::unmount();
+    serial_println!("[ok]");
+}
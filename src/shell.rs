@@ -0,0 +1,77 @@
+use alloc::string::String;
+use core::mem;
+use pc_keyboard::DecodedKey;
+use rust_os::{print, keyboard, vga_buffer};
+use crate::interpret::Environment;
+use crate::vm::Runtime;
+use crate::{lang, prelude};
+
+const PROMPT: &str = "# ";
+// a line too long for its row wraps onto the ones below, but no more of them
+// than fit on the screen, so backspace can still go back up to the prompt
+const MAX_LINE: usize = (vga_buffer::BUFFER_HEIGHT - 1) * vga_buffer::BUFFER_WIDTH - PROMPT.len();
+// `disassemble {code}` prints the bytecode for `code` instead of running it
+const DISASSEMBLE: &str = "disassemble ";
+
+// Reads and evaluates lines typed at the keyboard forever.
+pub fn run() -> ! {
+    let mut shell = Shell::new();
+    prompt();
+    loop {
+        shell.handle_key(keyboard::read_key());
+    }
+}
+
+// The line being typed, and the bindings and runtime every line entered so
+// far has shared.
+pub struct Shell {
+    env: Environment,
+    runtime: Runtime,
+    line: String,
+}
+
+impl Shell {
+    pub fn new() -> Shell {
+        let mut env = Environment::new();
+        prelude::install(&mut env);
+        Shell {
+            env,
+            runtime: Runtime::new(),
+            line: String::new(),
+        }
+    }
+
+    pub fn handle_key(&mut self, key: DecodedKey) {
+        match key {
+            DecodedKey::Unicode('\n') => {
+                print!("\n");
+                let line = mem::replace(&mut self.line, String::new());
+                if line.starts_with(DISASSEMBLE) {
+                    lang::disassemble(&String::from(&line[DISASSEMBLE.len()..]));
+                } else {
+                    lang::run(&line, &mut self.env, &mut self.runtime);
+                }
+                prompt();
+            },
+            DecodedKey::Unicode('\x08') => {
+                if self.line.pop().is_some() {
+                    print!("\x08");
+                }
+            },
+            DecodedKey::Unicode(c) if !c.is_control() && self.line.chars().count() < MAX_LINE => {
+                self.line.push(c);
+                print!("{}", c);
+            },
+            _ => (),
+        }
+    }
+}
+
+// Prints the prompt at the start of a row, after whatever the last line
+// printed without a newline.
+fn prompt() {
+    if vga_buffer::column() != 0 {
+        print!("\n");
+    }
+    print!("{}", PROMPT);
+}
@@ -1,56 +1,320 @@
-use crate::ast;
-use crate::interpret::{Evaluatable, Environment};
-use alloc::{boxed::Box, string::String};
-use core::fmt;
+use crate::interpret::{EvalResult, LangError};
+use crate::scan::{self, Span};
+use crate::vm::{Closure, Task};
+use alloc::{boxed::Box, vec::Vec, string::{String, ToString}, rc::Rc, collections::BTreeMap};
+use core::{cmp::Ordering, fmt, mem};
 
+// Values are never changed once made, so everything bigger than a number is
+// shared by reference counting: copying a value, like each time a variable
+// is read, only bumps a count.
 #[derive(Debug)]
 #[derive(Clone)]
 pub enum LangValue {
+    // an exact integer, like `1` or `0xff`
+    LangInt(i64),
+    // a float, like `1.5`
     LangNumber(f64),
-    LangString(String),
-    LangPair {left: Box<LangValue>, right: Box<LangValue>},
-    // LangFunc {arg_name: String},
+    LangString(Rc<str>),
+    // a Unicode scalar value
+    LangChar(char),
+    LangBool(bool),
+    // a cell of a list, whose tail other lists can share
+    LangPair(Rc<Pair>),
+    // `#[a, b]`, stored contiguously; it is never changed once made, so
+    // copies share it
+    LangVector(Rc<Vec<LangValue>>),
+    // `{name: a, size: b}`, with its fields kept in key order
+    LangRecord(Rc<BTreeMap<String, LangValue>>),
+    // a function compiled for the VM
+    LangClosure(Rc<Closure>),
+    // a builtin written in Rust, with the arguments it has been given so far
+    Native {func: Rc<NativeFunc>, args: Rc<[LangValue]>},
+    // `first >> second`
+    LangComposed {first: Rc<LangValue>, second: Rc<LangValue>},
     LangNone,
 }
+
+#[derive(Debug)]
+pub struct Pair {
+    pub left: LangValue,
+    pub right: LangValue,
+}
+
+// Unlinks the cells of a list nobody else shares one at a time, since
+// dropping a long list recursively could overflow the kernel stack.
+impl Drop for Pair {
+    fn drop(&mut self) {
+        let mut rest = mem::replace(&mut self.right, LangValue::LangNone);
+        while let LangValue::LangPair(pair) = rest {
+            rest = match Rc::try_unwrap(pair) {
+                Ok(mut pair) => mem::replace(&mut pair.right, LangValue::LangNone),
+                Err(_) => break,
+            };
+        }
+    }
+}
+
+impl LangValue {
+    pub fn pair(left: LangValue, right: LangValue) -> LangValue {
+        LangValue::LangPair(Rc::new(Pair { left, right }))
+    }
+
+    // The list of `items`, in order.
+    pub fn list(mut items: Vec<LangValue>) -> LangValue {
+        let mut out = LangValue::LangNone;
+        while let Some(item) = items.pop() {
+            out = LangValue::pair(item, out);
+        }
+        out
+    }
+
+
+    // How the value reads as text, like when printed or interpolated into a
+    // template: the same as its Display, except that strings aren't quoted.
+    pub fn show(&self) -> String {
+        match self {
+            LangValue::LangString(x) => x.to_string(),
+            LangValue::LangChar(x) => x.to_string(),
+            other => format!("{}", other),
+        }
+    }
+
+    // whether this is a chain of pairs ending in LangNone
+    fn is_list(&self) -> bool {
+        let mut rest = self;
+        loop {
+            rest = match rest {
+                LangValue::LangPair(pair) => &pair.right,
+                LangValue::LangNone => return true,
+                _ => return false,
+            };
+        }
+    }
+}
+
+// Values display the way they would be written in code where possible, so
+// the REPL can echo them unambiguously:
+//
+// [1, "two", nil]    a list
+// (1 . 2.5)          a pair that doesn't end a list
+// #[1, 2]            a vector
+// {a: 1, "b c": 2}   a record, quoting keys that aren't identifiers
+// <fn x>             a function, by its name or parameter
 impl fmt::Display for LangValue {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use LangValue::*;
         match self {
+            LangInt(x) => write!(f, "{}", x),
             LangNumber(x) => write!(f, "{:?}", x),
             LangString(x) => write!(f, "{:?}", x),
-            _ => write!(f, "<unknown val>"),
+            LangChar(x) => write!(f, "{:?}", x),
+            LangBool(x) => write!(f, "{}", x),
+            LangPair(pair) if self.is_list() => {
+                write!(f, "[{}", pair.left)?;
+                let mut rest = &pair.right;
+                while let LangPair(pair) = rest {
+                    write!(f, ", {}", pair.left)?;
+                    rest = &pair.right;
+                }
+                write!(f, "]")
+            },
+            LangPair(pair) => write!(f, "({} . {})", pair.left, pair.right),
+            LangVector(items) => {
+                write!(f, "#[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            },
+            LangRecord(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, val)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    if is_identifier(key) {
+                        write!(f, "{}: {}", key, val)?;
+                    } else {
+                        write!(f, "{:?}: {}", key, val)?;
+                    }
+                }
+                write!(f, "}}")
+            },
+            LangClosure(closure) => match &closure.function.name {
+                Some(name) if closure.function.binds_self => write!(f, "<fn {}>", name),
+                _ => write!(f, "<fn {}>", closure.function.param),
+            },
+            Native{func, ..} => write!(f, "<fn {}>", func.name),
+            LangComposed{first, second} => write!(f, "({} >> {})", first, second),
+            LangNone => write!(f, "nil"),
         }
     }
 }
-impl Evaluatable for LangValue {
-    fn eval(&self, env: &Environment) -> LangValue {
-        self.clone()
+// whether `key` scans as an identifier, so a record can show it unquoted
+pub fn is_identifier(key: &str) -> bool {
+    let mut chars = key.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() =>
+            chars.all(scan::is_identifier_char),
+        _ => false,
     }
 }
-impl ast::Expr for LangValue {}
 
-// pub struct LangFuncData {
-//     arg_name: String,
-//     env: Box<Environment>,
-//     ret: LangValue,
-// }
+// Integers equal the floats with exactly the same value, and functions are
+// only equal to themselves. Lists are compared a cell at a time rather than
+// recursively, so long ones don't overflow the kernel stack.
+impl PartialEq for LangValue {
+    fn eq(&self, other: &LangValue) -> bool {
+        use LangValue::*;
+        match (self, other) {
+            (LangInt(x), LangInt(y)) => x == y,
+            (LangNumber(x), LangNumber(y)) => x == y,
+            (LangInt(x), LangNumber(y)) | (LangNumber(y), LangInt(x)) =>
+                compare_int_float(*x, *y) == Some(Ordering::Equal),
+            (LangString(x), LangString(y)) => x == y,
+            (LangChar(x), LangChar(y)) => x == y,
+            (LangBool(x), LangBool(y)) => x == y,
+            (LangPair(x), LangPair(y)) => {
+                let (mut x, mut y) = (x, y);
+                loop {
+                    if x.left != y.left {
+                        return false;
+                    }
+                    match (&x.right, &y.right) {
+                        (LangPair(x_rest), LangPair(y_rest)) => {
+                            x = x_rest;
+                            y = y_rest;
+                        },
+                        (x_rest, y_rest) => return x_rest == y_rest,
+                    }
+                }
+            },
+            (LangVector(x), LangVector(y)) => x == y,
+            (LangRecord(x), LangRecord(y)) => x == y,
+            (LangClosure(x), LangClosure(y)) => Rc::ptr_eq(x, y),
+            (Native{func: f1, args: a1}, Native{func: f2, args: a2}) =>
+                Rc::ptr_eq(f1, f2) && a1 == a2,
+            (LangComposed{first: a, second: b}, LangComposed{first: c, second: d}) =>
+                Rc::ptr_eq(a, c) && Rc::ptr_eq(b, d),
+            (LangNone, LangNone) => true,
+            _ => false,
+        }
+    }
+}
+
+// Orders an integer and a float by their exact values, rather than rounding
+// the integer to the nearest float first. NaN is unordered.
+pub fn compare_int_float(x: i64, y: f64) -> Option<Ordering> {
+    // 2^63, the first float past the integers
+    const LIMIT: f64 = 9223372036854775808.0;
+    if y.is_nan() {
+        None
+    } else if y >= LIMIT {
+        Some(Ordering::Less)
+    } else if y < -LIMIT {
+        Some(Ordering::Greater)
+    } else {
+        // `y` lies strictly between `whole - 1` and `whole + 1`, so only a
+        // tie needs the fraction
+        let whole = y as i64;
+        Some(x.cmp(&whole).then_with(|| (whole as f64).partial_cmp(&y).unwrap()))
+    }
+}
+
+// Gets all of a builtin's arguments at once, along with the span of the call
+// that supplied the last one.
+pub type NativeFn = fn(Vec<LangValue>, Span) -> EvalResult;
+
+// Like a NativeFn, but for a builtin that calls functions back: it returns
+// the task the VM runs to get the result.
+pub type TaskFn = fn(Vec<LangValue>, Span) -> Result<Box<dyn Task>, LangError>;
+
+pub enum Builtin {
+    Value(NativeFn),
+    Task(TaskFn),
+}
 
-// /*
-// LangFunc
+// A builtin registered with `Environment::register`. Like any other function
+// it takes one argument per call, and runs once it has `arity` of them.
+pub struct NativeFunc {
+    pub name: Rc<str>,
+    pub arity: usize,
+    pub func: Builtin,
+}
+impl fmt::Debug for NativeFunc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NativeFunc({}/{})", self.name, self.arity)
+    }
+}
+
+#[cfg(test)]
+use rust_os::{serial_print, serial_println};
 
+#[test_case]
+fn test_display_values() {
+    use crate::interpret::Environment;
+    use crate::lang::run_script;
 
-// \(x y z) ->
-//     x + y*z
+    serial_print!("test_display_values... ");
+    let text = r#"
+        add x y = x + y;
+        [[1, "two", [true]], [], [add, add 1, add >> add], `text`]
+    "#;
+    let val = run_script(text, &mut Environment::new()).expect("vm failed");
+    assert_eq!(format!("{}", val),
+        r#"[[1, "two", [true]], nil, [<fn add>, <fn y>, (<fn add> >> <fn add>)], "text"]"#);
 
+    // a composition is the same function as its copies
+    let text = "add x y = x + y; c = add >> add; [c == c, c == (add >> add)]";
+    let val = run_script(text, &mut Environment::new()).expect("vm failed");
+    assert_eq!(format!("{}", val), "[true, false]");
 
-// \x' -> {
-//     x = x';
-//     \y' -> {
-//         y = y';
-//         \z -> {
-//             x + y*z
-//         }
-//     }
-// }
+    let pair = LangValue::pair(LangValue::LangString(Rc::from("a")), LangValue::LangNumber(2.0));
+    assert_eq!(format!("{}", pair), r#"("a" . 2.0)"#);
+    assert_eq!(LangValue::LangString(Rc::from("a")).show(), "a");
+    assert_eq!(pair.show(), r#"("a" . 2.0)"#);
+    serial_println!("[ok]");
+}
 
-// */
+#[test_case]
+fn test_values_share_lists() {
+    use crate::interpret::Environment;
+    use crate::lang::run_script;
+    use crate::prelude;
+    use rust_os::allocator::heap_used;
+
+    serial_print!("test_values_share_lists... ");
+    let before = heap_used();
+    // long enough that copying, comparing or dropping it recursively would
+    // overflow the kernel stack, and copying it at all would fill the heap
+    let mut env = Environment::new();
+    prelude::install(&mut env);
+    env.insert(Rc::from("xs"), LangValue::list((0..600).map(LangValue::LangInt).collect()));
+    env.insert(Rc::from("text"), LangValue::LangString(Rc::from("x".repeat(8 * 1024))));
+    let used = heap_used();
+
+    let text = "
+        ys = xs;
+        zs = [0] ++ ys;
+        [xs == ys, tail zs == xs, len zs, [text, text] == [text, text]]
+    ";
+    let val = run_script(text, &mut env.clone()).expect("vm failed");
+    assert_eq!(format!("{}", val), "[true, true, 601, true]");
+    drop(val);
+    assert_eq!(heap_used(), used);
+
+    // and so do the arguments a builtin has been given so far
+    let val = run_script("join text", &mut env.clone()).expect("vm failed");
+    match (&val, &val.clone()) {
+        (LangValue::Native{args: a, ..}, LangValue::Native{args: b, ..}) => assert!(Rc::ptr_eq(a, b)),
+        _ => panic!("Not a builtin: {}", val),
+    }
+    drop(val);
+    assert_eq!(heap_used(), used);
+    drop(env);
+    assert_eq!(heap_used(), before);
+    serial_println!("[ok]");
+}
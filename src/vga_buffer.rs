@@ -28,16 +28,24 @@ pub fn _print(args: fmt::Arguments) {
     });
 }
 
+// The column the next character will be written to on the bottom row.
+pub fn column() -> usize {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| WRITER.lock().column_position)
+}
+
 lazy_static! {
     pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
         column_position: 0,
+        wrapped_rows: 0,
         color_code: ColorCode::new(Color::Yellow, Color::Black),
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
     });
 }
 
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
 
 
 struct Buffer {
@@ -46,6 +54,9 @@ struct Buffer {
 
 pub struct Writer {
     column_position: usize,
+    // how many rows the text since the last newline has wrapped onto, so
+    // backspace can go back up to them
+    wrapped_rows: usize,
     color_code: ColorCode,
     buffer: &'static mut Buffer,
 }
@@ -54,8 +65,8 @@ impl Writer {
     pub fn write_string(&mut self, s: &str) {
         for byte in s.bytes() {
             match byte {
-                // printable ASCII byte or newline
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
+                // printable ASCII byte, newline or backspace
+                0x20..=0x7e | b'\n' | 0x08 => self.write_byte(byte),
                 // not part of printable ASCII range
                 _ => self.write_byte(0xfe),
             }
@@ -65,10 +76,15 @@ impl Writer {
 
     pub fn write_byte(&mut self, byte: u8) {
         match byte {
-            b'\n' => self.new_line(),
+            b'\n' => {
+                self.new_line();
+                self.wrapped_rows = 0;
+            },
+            0x08 => self.backspace(),
             byte => {
                 if self.column_position >= BUFFER_WIDTH {
                     self.new_line();
+                    self.wrapped_rows += 1;
                 }
 
                 let row = BUFFER_HEIGHT - 1;
@@ -95,6 +111,39 @@ impl Writer {
         self.column_position = 0;
     }
 
+    // Scrolls everything back down a row, undoing a wrap. The row that had
+    // scrolled off the top is gone, so a blank one takes its place.
+    fn previous_line(&mut self) {
+        for row in (1..BUFFER_HEIGHT).rev() {
+            for col in 0..BUFFER_WIDTH {
+                let character = self.buffer.chars[row - 1][col].read();
+                self.buffer.chars[row][col].write(character);
+            }
+        }
+        self.clear_row(0);
+        self.column_position = BUFFER_WIDTH;
+    }
+
+    // Erases the previous character on the current line, if there is one,
+    // going back up to the end of the row before if the line wrapped there.
+    fn backspace(&mut self) {
+        if self.column_position == 0 {
+            if self.wrapped_rows == 0 {
+                return;
+            }
+            self.previous_line();
+            self.wrapped_rows -= 1;
+        }
+        self.column_position -= 1;
+
+        let row = BUFFER_HEIGHT - 1;
+        let col = self.column_position;
+        self.buffer.chars[row][col].write(ScreenChar {
+            ascii_character: b' ',
+            color_code: self.color_code,
+        });
+    }
+
     fn clear_row(&mut self, row: usize) {
         let blank = ScreenChar {
             ascii_character: b' ',
@@ -192,4 +241,52 @@ fn test_println_output() {
     });
 
     serial_println!("[ok]");
-}
\ No newline at end of file
+}
+
+#[test_case]
+fn test_println_backspace() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    serial_print!("test_println_backspace... ");
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writeln!(writer, "\nab\x08c").expect("writeln failed");
+        let row = &writer.buffer.chars[BUFFER_HEIGHT - 2];
+        assert_eq!(char::from(row[0].read().ascii_character), 'a');
+        assert_eq!(char::from(row[1].read().ascii_character), 'c');
+        assert_eq!(char::from(row[2].read().ascii_character), ' ');
+    });
+
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_println_backspace_wrapped() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    serial_print!("test_println_backspace_wrapped... ");
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_str("\n").expect("write failed");
+        for _ in 0..BUFFER_WIDTH {
+            writer.write_byte(b'a');
+        }
+        // wraps onto a new row, then goes back up to the end of the last one
+        write!(writer, "b\x08\x08c").expect("write failed");
+        let row = &writer.buffer.chars[BUFFER_HEIGHT - 1];
+        assert_eq!(char::from(row[BUFFER_WIDTH - 2].read().ascii_character), 'a');
+        assert_eq!(char::from(row[BUFFER_WIDTH - 1].read().ascii_character), 'c');
+        // but no further back than the newline
+        for _ in 0..BUFFER_WIDTH + 1 {
+            writer.write_byte(0x08);
+        }
+        assert_eq!(writer.column_position, 0);
+        assert_eq!(writer.wrapped_rows, 0);
+    });
+
+    serial_println!("[ok]");
+}
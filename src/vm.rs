@@ -0,0 +1,745 @@
+use alloc::{boxed::Box, vec::Vec, string::{String, ToString}, rc::Rc, collections::BTreeMap};
+use core::{cell::RefCell, cmp, fmt, mem};
+use rust_os::allocator;
+use crate::compile::{Chunk, Function, Op};
+use crate::gc::{self, Marker};
+use crate::module;
+use crate::interpret::{self, Environment, EvalResult, LangError, MapList};
+use crate::scan::{Span, TokenType};
+use crate::value::{Builtin, LangValue::{self, *}};
+
+// A function compiled to bytecode, closed over the environment it was
+// created in. A named function's environment also binds the closure itself,
+// so it can recurse, and the functions declared after it in the same scope.
+// Closures are made in the runtime's `gc::Heap`, which frees the cycles that
+// makes.
+pub struct Closure {
+    pub function: Rc<Function>,
+    pub env: RefCell<Environment>,
+    pub mark: gc::Mark,
+}
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Closure({})", self.function.label())
+    }
+}
+
+// A builtin that calls functions back, like `map`. It doesn't call them
+// itself, since each call would need a VM of its own nested on the kernel
+// stack. Instead it runs in a frame of the VM that called it, a step at a
+// time: each step gets the result of the call the step before asked for,
+// and asks for another or finishes.
+pub trait Task {
+    // `result` is None for the first step.
+    fn step(&mut self, result: Option<LangValue>) -> Result<Next, LangError>;
+    // Marks the values the task holds, which a collection made while it
+    // runs mustn't free.
+    fn trace(&self, marker: &mut Marker);
+}
+
+pub enum Next {
+    // call the function with the argument
+    Call(LangValue, LangValue),
+    // collect garbage, and give the next step how many bytes that reclaimed
+    Collect,
+    Done(LangValue),
+}
+
+// How much of the kernel heap a call has to leave free. Every call's frame
+// and bindings live on the heap, so recursion goes as deep as the heap has
+// room for, and is stopped with an error before it runs the heap out.
+const RESERVE: usize = allocator::HEAP_SIZE / 32;
+
+// What lasts from one run of the VM to the next. The shell keeps one for
+// as long as it runs.
+pub struct Runtime {
+    // where the VM makes closures
+    heap: gc::Heap,
+    pub modules: module::Modules,
+    // the VMs waiting for an import to finish, outermost first
+    suspended: Vec<Suspended>,
+}
+
+impl Runtime {
+    pub fn new() -> Runtime {
+        Runtime {
+            heap: gc::Heap::new(),
+            modules: module::Modules::new(),
+            suspended: Vec::new(),
+        }
+    }
+
+    // Collects if the heap is filling up, with `env` as the roots besides
+    // the runtime's own, for when no VM is running.
+    pub fn collect_if_full(&mut self, env: &Environment) {
+        if self.heap.is_full() {
+            let mut marker = self.heap.marker();
+            marker.env(env);
+            self.trace(&mut marker);
+            self.heap.sweep(marker);
+        }
+    }
+
+    // Marks the modules imported so far and what the suspended VMs hold.
+    fn trace(&self, marker: &mut Marker) {
+        self.modules.trace(marker);
+        for vm in self.suspended.iter() {
+            marker.values(&vm.stack);
+            marker.env(&vm.env);
+            trace_frames(&vm.callers, &vm.scopes, marker);
+        }
+    }
+}
+
+struct CallFrame {
+    chunk: Rc<Chunk>,
+    // the next instruction to run
+    ip: usize,
+    env: Environment,
+    // the function's name and where it was called, or None for a script
+    call: Option<(Option<Rc<str>>, Span)>,
+    // how many scopes were saved before the frame started
+    scope_base: usize,
+    // the builtin the frame runs instead of its chunk, if it runs one, and
+    // the span of its call
+    task: Option<(Box<dyn Task>, Span)>,
+}
+
+impl CallFrame {
+    fn trace(&self, marker: &mut Marker) {
+        marker.env(&self.env);
+        if let Some((task, _)) = &self.task {
+            task.trace(marker);
+        }
+    }
+}
+
+fn trace_frames(frames: &[Box<CallFrame>], scopes: &[Environment], marker: &mut Marker) {
+    for frame in frames.iter() {
+        frame.trace(marker);
+    }
+    for env in scopes.iter() {
+        marker.env(env);
+    }
+}
+
+// What a VM holds while an import runs on a VM of its own. It's handed to
+// the runtime meanwhile, so collections made by the import still find it.
+struct Suspended {
+    stack: Vec<LangValue>,
+    // the running frame's environment; the rest of the frame stays put
+    env: Environment,
+    #[allow(clippy::vec_box)]
+    callers: Vec<Box<CallFrame>>,
+    scopes: Vec<Environment>,
+}
+
+// What the VM does while it has no code to run.
+enum Flow {
+    // call the function with the argument at the span
+    Call(LangValue, LangValue, Span),
+    // give the result of a call to the frame that made it
+    Return(LangValue),
+}
+
+pub struct Vm<'a> {
+    stack: Vec<LangValue>,
+    frame: CallFrame,
+    // boxed, so the stack of them never has much to copy as it grows
+    #[allow(clippy::vec_box)]
+    callers: Vec<Box<CallFrame>>,
+    // the environments saved by PushScope
+    scopes: Vec<Environment>,
+    runtime: &'a mut Runtime,
+}
+
+// Runs a compiled script directly in `env`, so declarations outlive it.
+pub fn run(chunk: Rc<Chunk>, env: &mut Environment, runtime: &mut Runtime) -> EvalResult {
+    let mut vm = Vm::new(CallFrame {
+        chunk,
+        ip: 0,
+        env: env.clone(),
+        call: None,
+        scope_base: 0,
+        task: None,
+    }, runtime);
+    let val = vm.execute()?;
+    *env = mem::replace(&mut vm.frame.env, Environment::new());
+    Ok(val)
+}
+
+// Sets up the frame for a call to `closure` made at `span`.
+fn enter(closure: &Rc<Closure>, arg: LangValue, span: Span) -> Result<CallFrame, LangError> {
+    let function = &closure.function;
+    let mut env = closure.env.borrow().clone();
+    function.param.destruct(&mut env, arg)
+        .map_err(|err| err.unwind(frame_name(&function.name), span))?;
+    Ok(CallFrame {
+        chunk: function.chunk.clone(),
+        ip: 0,
+        env,
+        call: Some((function.name.clone(), span)),
+        scope_base: 0,
+        task: None,
+    })
+}
+
+// The name an error shows for a frame, only copied once there is an error.
+fn frame_name(name: &Option<Rc<str>>) -> Option<String> {
+    name.as_ref().map(|name| String::from(&**name))
+}
+
+// How much more of the heap `vec` takes while it grows to fit another item,
+// holding its old buffer until the new one is filled.
+fn growth<T>(vec: &Vec<T>) -> usize {
+    if vec.len() < vec.capacity() {
+        return 0;
+    }
+    cmp::max(vec.capacity() * 2, 4) * mem::size_of::<T>()
+}
+
+// `(first >> second) arg`
+struct Compose {
+    // the functions left to call, last first
+    funcs: Vec<LangValue>,
+    arg: Option<LangValue>,
+}
+
+impl Task for Compose {
+    fn step(&mut self, result: Option<LangValue>) -> Result<Next, LangError> {
+        let val = result.or_else(|| self.arg.take()).expect("Composition without an argument");
+        Ok(match self.funcs.pop() {
+            Some(func) => Next::Call(func, val),
+            None => Next::Done(val),
+        })
+    }
+
+    fn trace(&self, marker: &mut Marker) {
+        marker.values(self.funcs.iter().chain(self.arg.iter()));
+    }
+}
+
+impl<'a> Vm<'a> {
+    fn new(frame: CallFrame, runtime: &'a mut Runtime) -> Vm<'a> {
+        Vm {
+            stack: Vec::new(),
+            frame,
+            callers: Vec::new(),
+            scopes: Vec::new(),
+            runtime,
+        }
+    }
+
+    fn execute(&mut self) -> EvalResult {
+        loop {
+            match self.step() {
+                Ok(None) => (),
+                Ok(Some(val)) => return Ok(val),
+                Err(err) => return Err(self.unwind(err)),
+            }
+        }
+    }
+
+    // Moves `err` out through every call still running.
+    fn unwind(&mut self, mut err: LangError) -> LangError {
+        loop {
+            if let Some((name, span)) = self.frame.call.take() {
+                err = err.unwind(frame_name(&name), span);
+            }
+            match self.callers.pop() {
+                Some(caller) => self.frame = *caller,
+                None => return err,
+            }
+        }
+    }
+
+    // Makes `callee`, called at `span`, the running frame.
+    fn push_frame(&mut self, mut callee: CallFrame, span: Span) -> Result<(), LangError> {
+        if !self.has_room_for_call() {
+            // what fills the heap may just be closures no longer used
+            self.collect(Some(&callee));
+            if !self.has_room_for_call() {
+                return Err(LangError::new(String::from("Stack overflow"), span));
+            }
+        }
+        callee.scope_base = self.scopes.len();
+        let caller = mem::replace(&mut self.frame, callee);
+        self.callers.push(Box::new(caller));
+        Ok(())
+    }
+
+    // Runs `task` in a frame of its own, which errors show as a call to
+    // `name` if it has one.
+    fn start_task(&mut self, task: Box<dyn Task>, name: Option<Rc<str>>, span: Span) -> Result<Flow, LangError> {
+        let frame = CallFrame {
+            // the frame never runs its chunk, but needs one all the same
+            chunk: self.frame.chunk.clone(),
+            ip: 0,
+            env: Environment::new(),
+            call: name.map(|name| (Some(name), span)),
+            scope_base: 0,
+            task: Some((task, span)),
+        };
+        self.push_frame(frame, span)?;
+        self.step_task(None)
+    }
+
+    // Runs the next step of the running frame's task, leaving the frame
+    // once the task is done.
+    fn step_task(&mut self, result: Option<LangValue>) -> Result<Flow, LangError> {
+        let (task, span) = self.frame.task.as_mut().expect("No task to step");
+        let span = *span;
+        match task.step(result)? {
+            Next::Call(func, arg) => Ok(Flow::Call(func, arg, span)),
+            // the result goes back to the same task
+            Next::Collect => Ok(Flow::Return(LangInt(self.collect(None) as i64))),
+            Next::Done(val) => {
+                self.frame = *self.callers.pop().expect("Task without a caller");
+                Ok(Flow::Return(val))
+            },
+        }
+    }
+
+    // Carries on from `flow` until there's code to run again. Closures get
+    // a frame to run in, and builtins are called straight away or run as
+    // tasks, so none of them need the kernel stack to call back.
+    fn proceed(&mut self, mut flow: Flow) -> Result<(), LangError> {
+        loop {
+            flow = match flow {
+                Flow::Call(LangClosure(closure), arg, span) => {
+                    let callee = enter(&closure, arg, span)?;
+                    return self.push_frame(callee, span);
+                },
+                Flow::Call(Native{func, args}, arg, span) => {
+                    // the partial application called keeps its own arguments
+                    let mut args = args.to_vec();
+                    args.push(arg);
+                    if args.len() < func.arity {
+                        Flow::Return(Native{func, args: Rc::from(args)})
+                    } else {
+                        let unwind = |err: LangError| err.unwind(Some(String::from(&*func.name)), span);
+                        match &func.func {
+                            Builtin::Value(native) =>
+                                Flow::Return(native(args, span).map_err(unwind)?),
+                            Builtin::Task(start) => {
+                                let task = start(args, span).map_err(unwind)?;
+                                self.start_task(task, Some(func.name.clone()), span)?
+                            },
+                        }
+                    }
+                },
+                Flow::Call(LangComposed{first, second}, arg, span) => {
+                    let task = Compose { funcs: vec![(*second).clone(), (*first).clone()], arg: Some(arg) };
+                    self.start_task(Box::new(task), None, span)?
+                },
+                Flow::Call(func, _, span) =>
+                    return Err(LangError::new(format!("Cannot call {}", func), span)),
+                Flow::Return(val) if self.frame.task.is_some() => self.step_task(Some(val))?,
+                Flow::Return(val) => {
+                    self.stack.push(val);
+                    return Ok(());
+                },
+            };
+        }
+    }
+
+    // Collects garbage, with everything this VM and the ones it's suspended
+    // hold as the roots, along with `callee` if a call is about to start it,
+    // returning how many bytes that reclaimed.
+    fn collect(&mut self, callee: Option<&CallFrame>) -> usize {
+        let mut marker = self.runtime.heap.marker();
+        marker.values(&self.stack);
+        trace_frames(&self.callers, &self.scopes, &mut marker);
+        self.frame.trace(&mut marker);
+        if let Some(callee) = callee {
+            callee.trace(&mut marker);
+        }
+        self.runtime.trace(&mut marker);
+        self.runtime.heap.sweep(marker)
+    }
+
+    // Whether the heap has room for another call: for the caller's frame,
+    // for the stacks the VM keeps its calls, scopes and values on to grow,
+    // and for the reserve besides.
+    fn has_room_for_call(&self) -> bool {
+        let needed = mem::size_of::<CallFrame>() + growth(&self.callers) + growth(&self.scopes)
+            + growth(&self.stack) + RESERVE;
+        allocator::heap_used() + needed <= allocator::HEAP_SIZE
+    }
+
+    // Hands what this VM holds to the runtime while an import runs.
+    fn suspend(&mut self) {
+        self.runtime.suspended.push(Suspended {
+            stack: mem::take(&mut self.stack),
+            env: mem::replace(&mut self.frame.env, Environment::new()),
+            callers: mem::take(&mut self.callers),
+            scopes: mem::take(&mut self.scopes),
+        });
+    }
+
+    fn resume(&mut self) {
+        let vm = self.runtime.suspended.pop().expect("No VM to resume");
+        self.stack = vm.stack;
+        self.frame.env = vm.env;
+        self.callers = vm.callers;
+        self.scopes = vm.scopes;
+    }
+
+    fn pop(&mut self) -> LangValue {
+        self.stack.pop().expect("Stack underflow")
+    }
+
+    // Runs one instruction, returning the result once the outermost frame returns.
+    fn step(&mut self) -> Result<Option<LangValue>, LangError> {
+        let op = self.frame.chunk.code[self.frame.ip];
+        let span = self.frame.chunk.spans[self.frame.ip];
+        self.frame.ip += 1;
+
+        match op {
+            Op::Constant(i) => {
+                let val = self.frame.chunk.constants[i as usize].clone();
+                self.stack.push(val);
+            },
+            Op::Nil => self.stack.push(LangNone),
+            // a function sees the bindings from where it was defined, not
+            // where it's called
+            Op::Load(i) => {
+                let name = &self.frame.chunk.names[i as usize];
+                match self.frame.env.get(name) {
+                    Some(val) => self.stack.push(val.clone()),
+                    None => return Err(LangError::new(
+                        format!("Unbound identifier `{}` on line {}", name, span.line), span)),
+                }
+            },
+            Op::Bind(i) => {
+                let val = self.pop();
+                self.frame.chunk.patterns[i as usize].destruct(&mut self.frame.env, val)?;
+            },
+            Op::Pop => {
+                self.pop();
+            },
+            Op::Swap => {
+                let len = self.stack.len();
+                self.stack.swap(len - 1, len - 2);
+            },
+            Op::PushScope => self.scopes.push(self.frame.env.clone()),
+            Op::PopScope => self.frame.env = self.scopes.pop().expect("Unbalanced scopes"),
+            Op::Closure(i) => {
+                if self.runtime.heap.is_full() {
+                    self.collect(None);
+                }
+                let function = self.frame.chunk.functions[i as usize].clone();
+                let closure = self.runtime.heap.alloc(function, self.frame.env.clone());
+                match &closure.function.name {
+                    Some(name) if closure.function.binds_self => {
+                        closure.env.borrow_mut().insert(name.clone(), LangClosure(closure.clone()));
+                    },
+                    _ => (),
+                }
+                self.stack.push(LangClosure(closure));
+            },
+            Op::Link(i, j) => {
+                let name = &self.frame.chunk.names[i as usize];
+                let function = &self.frame.chunk.functions[j as usize];
+                // the name may have been rebound to something else since,
+                // which this scope doesn't own
+                if let (Some(LangClosure(earlier)), Some(LangClosure(closure))) =
+                    (self.frame.env.get(name), self.stack.last()) {
+                    match &closure.function.name {
+                        Some(name) if Rc::ptr_eq(&earlier.function, function) => {
+                            earlier.env.borrow_mut().insert(name.clone(), LangClosure(closure.clone()));
+                        },
+                        _ => (),
+                    }
+                }
+            },
+            Op::Call => {
+                let arg = self.pop();
+                let func = self.pop();
+                self.proceed(Flow::Call(func, arg, span))?;
+            },
+            Op::TailCall => {
+                let arg = self.pop();
+                match self.pop() {
+                    LangClosure(closure) => {
+                        let mut callee = enter(&closure, arg, span)?;
+                        // an error in the callee now leaves through the call
+                        // site of the frame it replaces
+                        let (_, call_span) = self.frame.call.take().expect("Tail call in a script");
+                        callee.call = Some((closure.function.name.clone(), call_span));
+                        callee.scope_base = self.frame.scope_base;
+                        self.scopes.truncate(callee.scope_base);
+                        self.frame = callee;
+                    },
+                    // anything else has a frame of its own, if it needs one
+                    func => self.proceed(Flow::Call(func, arg, span))?,
+                }
+            },
+            // the result is left on the stack for the caller
+            Op::Return => {
+                let val = self.pop();
+                match self.callers.pop() {
+                    Some(caller) => {
+                        self.frame = *caller;
+                        self.proceed(Flow::Return(val))?;
+                    },
+                    None => return Ok(Some(val)),
+                }
+            },
+            Op::Add | Op::Subtract | Op::Multiply | Op::Divide | Op::Concat |
+            Op::Equal | Op::NotEqual | Op::Less | Op::LessEqual | Op::Greater | Op::GreaterEqual => {
+                let right = self.pop();
+                let left = self.pop();
+                let oper = match op {
+                    Op::Add => TokenType::Plus,
+                    Op::Subtract => TokenType::Minus,
+                    Op::Multiply => TokenType::Star,
+                    Op::Divide => TokenType::Slash,
+                    Op::Concat => TokenType::PlusPlus,
+                    Op::Equal => TokenType::EqualEqual,
+                    Op::NotEqual => TokenType::NotEqual,
+                    Op::Less => TokenType::Less,
+                    Op::LessEqual => TokenType::LessEq,
+                    Op::Greater => TokenType::Greater,
+                    _ => TokenType::GreaterEq,
+                };
+                let val = interpret::binary(oper, left, right, span)?;
+                self.stack.push(val);
+            },
+            Op::Negate | Op::Not => {
+                let operand = self.pop();
+                let oper = if op == Op::Negate { TokenType::Minus } else { TokenType::Bang };
+                let val = interpret::unary(oper, operand, span)?;
+                self.stack.push(val);
+            },
+            Op::Compose => {
+                let second = self.pop();
+                let first = self.pop();
+                self.stack.push(LangComposed {
+                    first: Rc::new(first),
+                    second: Rc::new(second),
+                });
+            },
+            Op::Pair => {
+                let right = self.pop();
+                let left = self.pop();
+                self.stack.push(LangValue::pair(left, right));
+            },
+            Op::Jump(target) => self.frame.ip = target as usize,
+            Op::JumpIfFalse(target) => match self.pop() {
+                LangBool(true) => (),
+                LangBool(false) => self.frame.ip = target as usize,
+                other => return Err(LangError::new(
+                    format!("Expecting a boolean, not {}", other), span)),
+            },
+            Op::Case(i, target) => {
+                let pattern = self.frame.chunk.patterns[i as usize].clone();
+                if pattern.matches(self.stack.last().expect("Stack underflow")) {
+                    let val = self.pop();
+                    self.scopes.push(self.frame.env.clone());
+                    pattern.destruct(&mut self.frame.env, val)?;
+                } else {
+                    self.frame.ip = target as usize;
+                }
+            },
+            Op::NoCase => {
+                let val = self.pop();
+                return Err(LangError::new(format!("No case for {}", val), span));
+            },
+            Op::Template(n) => {
+                let parts = self.stack.split_off(self.stack.len() - n as usize);
+                let mut out = String::new();
+                for part in parts {
+                    out.push_str(&part.show());
+                }
+                self.stack.push(LangString(Rc::from(out)));
+            },
+            Op::Map => {
+                let list = self.pop();
+                let func = self.pop();
+                let flow = self.start_task(Box::new(MapList::new(func, list, span)), None, span)?;
+                self.proceed(flow)?;
+            },
+            Op::Vector(n) => {
+                let items = self.stack.split_off(self.stack.len() - n as usize);
+                self.stack.push(LangVector(Rc::new(items)));
+            },
+            Op::Record(n) => {
+                let mut parts = self.stack.split_off(self.stack.len() - 2 * n as usize).into_iter();
+                let mut fields: BTreeMap<String, LangValue> = BTreeMap::new();
+                while let (Some(LangString(key)), Some(val)) = (parts.next(), parts.next()) {
+                    fields.insert(key.to_string(), val);
+                }
+                self.stack.push(LangRecord(Rc::new(fields)));
+            },
+            Op::Import(i) => {
+                let path = match &self.frame.chunk.constants[i as usize] {
+                    LangString(path) => path.clone(),
+                    other => panic!("Cannot import {}", other),
+                };
+                self.suspend();
+                let val = module::import(&path, span, self.runtime);
+                self.resume();
+                self.stack.push(val?);
+            },
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+use rust_os::{serial_print, serial_println};
+
+#[test_case]
+fn test_vm_programs() {
+    use crate::lang::run_script;
+
+    serial_print!("test_vm_programs... ");
+    let programs = [
+        ("a = 1; b = { c = 2; a + c * 3 }; (b - a) / 2", "3"),
+        ("add x y = x + y; helper x = add 2 x; helper 3", "5"),
+        ("[a, _ | rest] = [1, 2, 3, 4]; [c, d] = rest; a + c + d", "8"),
+        ("double x = x * 2; 3 |> \\x -> [x, x + 1] >> \\[a, b] -> a * b >> double |> double", "48"),
+        ("x * 2 for x in [1, 2, 3]", "[2, 4, 6]"),
+        ("double-all = \\xs -> x * 2 for x in xs; \
+            [double-all [1, 2], if false then [] else x for x in [3], case 0 of _ -> x for x in [4]]",
+            "[[2, 4], [3], [4]]"),
+        ("fact n = if n <= 1 then 1 else n * fact (n - 1); fact 5", "120"),
+        ("[1 < 2 && `a` < `b`, 2 >= 3 || !(1 == 1), -(2 - 5) != 3, `con` ++ `cat`, [1] ++ [2, 3]]",
+            r#"[true, false, false, "concat", [1, 2, 3]]"#),
+        ("[false && undefined, true || undefined]", "[false, true]"),
+        ("count xs = case xs of [] -> 0, [_ | rest] -> 1 + count rest; count [1, 2, 3]", "3"),
+        ("name = `vm`; `hello $name, $(1 + 1)`", r#""hello vm, 2""#),
+        ("{size, \"the name\": #[a | rest]} = {size: 3, \"the name\": #[1, 2]}; #[a, rest, size, {}]",
+            "#[1, #[2], 3, {}]"),
+        ("sub = \\[x, [y]] -> x - y; sub [5, [2]]", "3"),
+        ("[x, y] = x * 2 for x in [1, 2]; [p, q] = for [a, b] in [[1, 2], [3, 4]] { a * b }; x + y + p + q",
+            "20"),
+        ("user-name' = `world`; `hello $user-name', $(`nested $(1 + 1)`) \\$x`",
+            r#""hello world, nested 2 $x""#),
+        ("describe x = case x of [true, _] -> 1, [_, \"b\" | rest] -> 2, _ -> 3; \
+            [describe [true, 0], describe [1, \"b\", 2], describe \"c\"]", "[1, 2, 3]"),
+        ("-2 * 3 + 4 == -2 && `a` ++ `b` >= `ab` || undefined", "true"),
+    ];
+    for (program, expected) in programs.iter() {
+        let val = run_script(program, &mut Environment::new()).expect("vm failed");
+        assert_eq!(format!("{}", val), *expected);
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_vm_error_frames() {
+    use crate::lang::run_script;
+
+    serial_print!("test_vm_error_frames... ");
+    let text = "add x y = x + y;\nf z = 1 + add z true;\nf 1";
+    let err = run_script(text, &mut Environment::new()).expect_err("vm succeeded");
+    assert_eq!(err.message, "NaN true");
+    assert_eq!((err.span.line, err.span.column), (3, 1));
+    assert_eq!(err.frames.len(), 2);
+    assert_eq!(err.frames[0].name, Some(String::from("add")));
+    assert_eq!((err.frames[0].span.line, err.frames[0].span.column), (1, 11));
+    assert_eq!(err.frames[1].name, Some(String::from("f")));
+    assert_eq!((err.frames[1].span.line, err.frames[1].span.column), (2, 11));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_vm_tail_calls() {
+    use crate::lang::run_script;
+
+    serial_print!("test_vm_tail_calls... ");
+    // a frame for every call would need far more than the whole heap
+    let text = "
+        loop n = case n of 0 -> `done`, _ -> { m = n - 1; loop m };
+        count n = if true then loop n else 0;
+        count 100000
+    ";
+    let val = run_script(text, &mut Environment::new()).expect("vm failed");
+    assert_eq!(val, LangString(Rc::from("done")));
+
+    // functions declared together can call each other, in a block too
+    let text = "
+        even n = if n == 0 then true else odd (n - 1);
+        odd n = if n == 0 then false else even (n - 1);
+        parity n = {
+            ping n = case n of 0 -> `ping`, _ -> pong (n - 1);
+            pong n = case n of 0 -> `pong`, _ -> ping (n - 1);
+            ping n
+        };
+        [even 100000, odd 100001, even 7, parity 100001]
+    ";
+    let val = run_script(text, &mut Environment::new()).expect("vm failed");
+    assert_eq!(format!("{}", val), r#"[true, true, false, "pong"]"#);
+
+    // but a function redeclared under the same name doesn't change the old one
+    let text = "
+        fact n = if n <= 1 then 1 else n * fact (n - 1);
+        old = fact;
+        fact n = 0;
+        [old 5, fact 5]
+    ";
+    let val = run_script(text, &mut Environment::new()).expect("vm failed");
+    assert_eq!(format!("{}", val), "[120, 0]");
+
+    // and a partial application made before the function it calls was
+    // declared doesn't see it
+    let text = "
+        f x y = g y;
+        h = f 1;
+        g y = 2;
+        [f 1 3, h 3]
+    ";
+    let err = run_script(text, &mut Environment::new()).expect_err("vm succeeded");
+    assert_eq!(err.message, "Unbound identifier `g` on line 2");
+    assert_eq!(err.frames[0].name, Some(String::from("f")));
+    let text = "f x y = g y; h = f 1; g y = 2; f 1 3";
+    assert_eq!(run_script(text, &mut Environment::new()).expect("vm failed"), LangInt(2));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_vm_stack_overflow() {
+    use crate::lang::run_script;
+    use crate::prelude;
+    use rust_os::allocator::heap_used;
+
+    serial_print!("test_vm_stack_overflow... ");
+    let decls = "
+        f n = if n == 0 then 0 else 1 + f (n - 1);
+        g n = if n == 0 then 0 else head (map (\\_ -> 1 + g (n - 1)) [0]);
+        upto n acc = if n == 0 then acc else upto (n - 1) ([n] ++ acc);
+        count xs = case xs of [] -> 0, [_ | rest] -> 1 + count rest;
+    ";
+    let run_text = |text| {
+        let mut env = Environment::new();
+        prelude::install(&mut env);
+        run_script(&format!("{}{}", decls, text), &mut env)
+    };
+    // recursion goes as deep as the heap has room for
+    let val = run_text("[f 100, g 40, count (upto 200 [])]").expect("vm failed");
+    assert_eq!(format!("{}", val), "[100, 40, 200]");
+
+    // calls made by builtins count too, since they also hold their frames
+    // until they return
+    let before = heap_used();
+    for text in ["f 1000", "g 1000"].iter() {
+        let err = run_text(text).expect_err("vm succeeded");
+        assert_eq!(err.message, "Stack overflow");
+    }
+    assert_eq!(heap_used(), before);
+
+    // and they run on the same VM, so reaching the limit through `map`
+    // doesn't take any more of the kernel stack than a plain call
+    let err = run_text("g 1000").expect_err("vm succeeded");
+    assert!(err.frames.iter().any(|frame| frame.name.as_deref() == Some("map")));
+    assert!(err.skipped > 120);
+
+    // either way the message stays on screen
+    assert_eq!(format!("{}", err).lines().count(), 8);
+    let err = run_text("f 1000").expect_err("vm succeeded");
+    assert!(format!("{}", err).starts_with("Stack overflow\n  in f on line 2, column 41 ("));
+    assert!(err.frames[0].times > 200);
+    serial_println!("[ok]");
+}